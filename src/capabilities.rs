@@ -0,0 +1,61 @@
+//! Finding the validation capabilities each entry point needs, for `--report-capabilities-per-entry`.
+
+use std::collections::HashSet;
+
+use naga::{valid::Capabilities, Arena, Block, Function, Handle};
+
+use crate::visit::for_each_call;
+
+/// The names of the capabilities without which the entry point at `index` fails validation.
+///
+/// The entry point is validated on its own: the other entry points are removed, along with the bodies of functions
+/// it doesn't call and the types only they used. Global variables and constants are kept, so capabilities they
+/// need are reported for every entry point.
+pub fn required_capabilities(module: &naga::Module, index: usize) -> Vec<&'static str> {
+    let mut module = module.clone();
+    let entry_point = module.entry_points.swap_remove(index);
+    module.entry_points = vec![entry_point];
+
+    let called = called_functions(
+        &mut module.functions,
+        &mut module.entry_points[0].function.body,
+    );
+    for (handle, function) in module.functions.iter_mut() {
+        if !called.contains(&handle) {
+            *function = Function {
+                name: function.name.take(),
+                ..Function::default()
+            };
+        }
+    }
+    naga::compact::compact(&mut module);
+
+    let validates = |capabilities| {
+        naga::valid::Validator::new(naga::valid::ValidationFlags::all(), capabilities)
+            .validate(&module)
+            .is_ok()
+    };
+    Capabilities::all()
+        .iter_names()
+        .filter(|&(_, capability)| !validates(Capabilities::all() - capability))
+        .map(|(name, _)| name)
+        .collect()
+}
+
+/// The functions called by `body`, directly or indirectly.
+fn called_functions(
+    functions: &mut Arena<Function>,
+    body: &mut Block,
+) -> HashSet<Handle<Function>> {
+    let mut called = HashSet::new();
+    let mut pending = Vec::new();
+    for_each_call(body, &mut |callee| pending.push(*callee));
+    while let Some(handle) = pending.pop() {
+        if called.insert(handle) {
+            for_each_call(&mut functions[handle].body, &mut |callee| {
+                pending.push(*callee)
+            });
+        }
+    }
+    called
+}
@@ -0,0 +1,193 @@
+//! Line diffs between generated output and a previously generated artifact, for `--diff-against`.
+
+/// Number of unchanged lines shown around each change.
+const CONTEXT: usize = 3;
+/// Largest number of cells in the table used to align the changed region. Larger regions are
+/// shown as a single replacement rather than aligned line by line.
+const MAX_TABLE_CELLS: usize = 1 << 24;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Same,
+    Removed,
+    Added,
+}
+
+/// A unified diff from `old` to `new`, or `None` if they are identical.
+pub fn unified_diff(old_name: &str, new_name: &str, old: &str, new: &str) -> Option<String> {
+    if old == new {
+        return None;
+    }
+    let old: Vec<_> = old.lines().collect();
+    let new: Vec<_> = new.lines().collect();
+    let lines = diff_lines(&old, &new);
+
+    let mut diff = format!("--- {old_name}\n+++ {new_name}\n");
+    let changes: Vec<_> = (0..lines.len())
+        .filter(|&index| lines[index].0 != Op::Same)
+        .collect();
+    if changes.is_empty() {
+        // only line endings differ
+        diff.push_str("(line endings differ)\n");
+        return Some(diff);
+    }
+
+    let mut groups: Vec<(usize, usize)> = Vec::new();
+    for &change in changes.iter() {
+        match groups.last_mut() {
+            // hunks whose context would meet are merged
+            Some((_, last)) if change - *last <= 2 * CONTEXT + 1 => *last = change,
+            _ => groups.push((change, change)),
+        }
+    }
+
+    for (first, last) in groups {
+        let start = first.saturating_sub(CONTEXT);
+        let end = (last + CONTEXT + 1).min(lines.len());
+        let count =
+            |range: &[(Op, &str)], skip: Op| range.iter().filter(|(op, _)| *op != skip).count();
+        let (old_before, new_before) = (
+            count(&lines[..start], Op::Added),
+            count(&lines[..start], Op::Removed),
+        );
+        let (old_count, new_count) = (
+            count(&lines[start..end], Op::Added),
+            count(&lines[start..end], Op::Removed),
+        );
+        // an empty range is numbered by the line before it
+        let first_line = |before: usize, count: usize| before + usize::from(count > 0);
+        diff.push_str(&format!(
+            "@@ -{},{old_count} +{},{new_count} @@\n",
+            first_line(old_before, old_count),
+            first_line(new_before, new_count)
+        ));
+        for (op, line) in &lines[start..end] {
+            let prefix = match op {
+                Op::Same => ' ',
+                Op::Removed => '-',
+                Op::Added => '+',
+            };
+            diff.push(prefix);
+            diff.push_str(line);
+            diff.push('\n');
+        }
+    }
+    Some(diff)
+}
+
+/// Align `old` and `new` by a longest common subsequence of their lines.
+fn diff_lines<'a>(old: &[&'a str], new: &[&'a str]) -> Vec<(Op, &'a str)> {
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let old_middle = &old[prefix..old.len() - suffix];
+    let new_middle = &new[prefix..new.len() - suffix];
+
+    let mut lines: Vec<_> = old[..prefix].iter().map(|line| (Op::Same, *line)).collect();
+    let (rows, columns) = (old_middle.len() + 1, new_middle.len() + 1);
+    if rows * columns > MAX_TABLE_CELLS {
+        lines.extend(old_middle.iter().map(|line| (Op::Removed, *line)));
+        lines.extend(new_middle.iter().map(|line| (Op::Added, *line)));
+    } else {
+        // lengths of the longest common subsequences of the suffixes starting at each pair of lines
+        let mut table = vec![0u32; rows * columns];
+        for i in (0..old_middle.len()).rev() {
+            for j in (0..new_middle.len()).rev() {
+                table[i * columns + j] = if old_middle[i] == new_middle[j] {
+                    table[(i + 1) * columns + j + 1] + 1
+                } else {
+                    table[(i + 1) * columns + j].max(table[i * columns + j + 1])
+                };
+            }
+        }
+        let (mut i, mut j) = (0, 0);
+        while i < old_middle.len() || j < new_middle.len() {
+            if i < old_middle.len() && j < new_middle.len() && old_middle[i] == new_middle[j] {
+                lines.push((Op::Same, old_middle[i]));
+                i += 1;
+                j += 1;
+            } else if j == new_middle.len()
+                || (i < old_middle.len()
+                    && table[(i + 1) * columns + j] >= table[i * columns + j + 1])
+            {
+                lines.push((Op::Removed, old_middle[i]));
+                i += 1;
+            } else {
+                lines.push((Op::Added, new_middle[j]));
+                j += 1;
+            }
+        }
+    }
+    lines.extend(
+        old[old.len() - suffix..]
+            .iter()
+            .map(|line| (Op::Same, *line)),
+    );
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn numbered(lines: std::ops::RangeInclusive<usize>, changed: &[usize]) -> String {
+        lines
+            .map(|line| match changed.contains(&line) {
+                true => format!("changed {line}\n"),
+                false => format!("line {line}\n"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn hunk_header() {
+        let diff = unified_diff(
+            "old",
+            "new",
+            &numbered(1..=10, &[]),
+            &numbered(1..=10, &[5]),
+        );
+        assert_eq!(
+            diff.unwrap(),
+            "--- old\n+++ new\n@@ -2,7 +2,7 @@\n line 2\n line 3\n line 4\n-line 5\n+changed 5\n line 6\n line 7\n line 8\n"
+        );
+    }
+
+    #[test]
+    fn empty_ranges() {
+        assert_eq!(
+            unified_diff("old", "new", "", "a\n").unwrap(),
+            "--- old\n+++ new\n@@ -0,0 +1,1 @@\n+a\n"
+        );
+        assert_eq!(
+            unified_diff("old", "new", "a\n", "").unwrap(),
+            "--- old\n+++ new\n@@ -1,1 +0,0 @@\n-a\n"
+        );
+    }
+
+    #[test]
+    fn context_merging() {
+        let old = numbered(1..=20, &[]);
+        // changes with at most twice the context between them share a hunk
+        let merged = unified_diff("old", "new", &old, &numbered(1..=20, &[4, 11])).unwrap();
+        assert_eq!(merged.matches("@@ -").count(), 1);
+        assert!(merged.contains("@@ -1,14 +1,14 @@\n"));
+        let split = unified_diff("old", "new", &old, &numbered(1..=20, &[4, 12])).unwrap();
+        assert_eq!(split.matches("@@ -").count(), 2);
+        assert!(split.contains("@@ -1,7 +1,7 @@\n"));
+        assert!(split.contains("@@ -9,7 +9,7 @@\n"));
+    }
+
+    #[test]
+    fn line_endings_only() {
+        assert_eq!(
+            unified_diff("old", "new", "a\r\nb\r\n", "a\nb\n").unwrap(),
+            "--- old\n+++ new\n(line endings differ)\n"
+        );
+        assert_eq!(unified_diff("old", "new", "a\nb\n", "a\nb\n"), None);
+    }
+}
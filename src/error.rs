@@ -0,0 +1,256 @@
+//! Errors reported by the cli. Each kind of error maps to a documented exit code so that scripts can
+//! distinguish why a run failed.
+
+use std::{
+    fmt::Display,
+    ops::Range,
+    process::exit,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use naga_oil::compose::{Composer, ComposerError, ComposerErrorInner};
+
+use crate::log::Location;
+
+static EXPLAIN: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// Composition or validation of the shader failed.
+    Compose,
+    /// A file couldn't be read or written.
+    Io,
+    /// An argument or other input couldn't be parsed or is inconsistent.
+    Args,
+    /// A required import couldn't be found in the include paths.
+    UnresolvedImport,
+    /// A backend failed to write the output.
+    Backend,
+    /// Composition and validation took longer than `--timeout`.
+    Timeout,
+}
+
+impl ErrorKind {
+    pub fn exit_code(self) -> i32 {
+        match self {
+            ErrorKind::Compose => 1,
+            ErrorKind::Io => 2,
+            ErrorKind::Args => 3,
+            ErrorKind::UnresolvedImport => 4,
+            ErrorKind::Backend => 5,
+            ErrorKind::Timeout => 6,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Error {
+    pub kind: ErrorKind,
+    pub message: String,
+    /// A complete diagnostic (e.g. from naga_oil), printed in place of the message.
+    pub rendered: Option<String>,
+    pub location: Option<Box<Location>>,
+    /// A suggested fix, printed after the error with `--explain-error`.
+    pub hint: Option<&'static str>,
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    pub fn new(kind: ErrorKind, message: impl Display) -> Self {
+        Self {
+            kind,
+            message: message.to_string(),
+            rendered: None,
+            location: None,
+            hint: None,
+        }
+    }
+
+    pub fn with_hint(self, hint: &'static str) -> Self {
+        Self {
+            hint: Some(hint),
+            ..self
+        }
+    }
+
+    pub fn compose(message: impl Display) -> Self {
+        Self::new(ErrorKind::Compose, message)
+    }
+
+    pub fn io(message: impl Display) -> Self {
+        Self::new(ErrorKind::Io, message)
+    }
+
+    pub fn args(message: impl Display) -> Self {
+        Self::new(ErrorKind::Args, message)
+    }
+
+    pub fn unresolved_import(message: impl Display) -> Self {
+        Self::new(ErrorKind::UnresolvedImport, message)
+    }
+
+    pub fn backend(message: impl Display) -> Self {
+        Self::new(ErrorKind::Backend, message)
+    }
+
+    /// An error from the composer, rendered with its source context.
+    pub fn composer(e: &ComposerError, composer: &Composer) -> Self {
+        Self {
+            rendered: Some(e.emit_to_string(composer)),
+            location: composer_error_location(e, composer).map(Box::new),
+            hint: composer_error_hint(&e.inner),
+            ..Self::compose(&e.inner)
+        }
+    }
+
+    /// Print the error to stderr.
+    pub fn print(&self) {
+        crate::log::flush();
+        crate::log::clear_progress();
+        if crate::log::json() {
+            let diagnostic = crate::log::Diagnostic {
+                severity: crate::log::Severity::Error,
+                message: self.message.clone(),
+                location: self.location.as_deref().cloned(),
+            };
+            eprintln!("{}", diagnostic.to_json_line());
+            if let Some(hint) = self.explained_hint() {
+                eprintln!(
+                    "{}",
+                    serde_json::json!({ "level": "help", "event": "diagnostic", "message": hint })
+                );
+            }
+            return;
+        }
+        match &self.rendered {
+            Some(rendered) => eprintln!("{}", crate::log::colorize(rendered)),
+            None => eprintln!("error: {}", self.message),
+        }
+        if let Some(hint) = self.explained_hint() {
+            eprintln!("help: {hint}");
+        }
+    }
+
+    fn explained_hint(&self) -> Option<&'static str> {
+        self.hint.filter(|_| EXPLAIN.load(Ordering::Relaxed))
+    }
+
+    /// Print the error to stderr and exit with the corresponding exit code.
+    pub fn exit(&self) -> ! {
+        self.print();
+        exit(self.kind.exit_code())
+    }
+}
+
+/// Print a suggested fix after errors that have one.
+pub fn set_explain(enabled: bool) {
+    EXPLAIN.store(enabled, Ordering::Relaxed);
+}
+
+/// A short explanation and suggested fix for the common composer errors.
+fn composer_error_hint(inner: &ComposerErrorInner) -> Option<&'static str> {
+    Some(match inner {
+        ComposerErrorInner::ImportNotFound(..) => NOT_FOUND_HINT,
+        ComposerErrorInner::ImportParseError(..) => {
+            "`#import` takes a module name, optionally followed by `as` and an alias, or by `::{ ... }` and a list of items"
+        }
+        ComposerErrorInner::NotEnoughEndIfs(_)
+        | ComposerErrorInner::TooManyEndIfs(_)
+        | ComposerErrorInner::ElseWithoutCondition(_) => {
+            "each `#ifdef`, `#ifndef` or `#if` needs a matching `#endif`, with any `#else` between them; check how the conditions nest"
+        }
+        ComposerErrorInner::UnknownShaderDef { .. } => {
+            "`#if` compares the value of a def, so the def must be given; pass it with `--defs NAME=VALUE`, or test for it with `#ifdef` first"
+        }
+        ComposerErrorInner::UnknownShaderDefOperator { .. } => {
+            "`#if` supports the comparisons `==`, `!=`, `<`, `<=`, `>` and `>=`"
+        }
+        ComposerErrorInner::InvalidShaderDefComparisonValue { .. } => {
+            "the def's value has a different type from the value it is compared with; give the def with an explicit type, such as `-d NAME:u32=1`"
+        }
+        ComposerErrorInner::InconsistentShaderDefValue { .. } => {
+            "the def is given different values; give it a single value with `--defs`"
+        }
+        ComposerErrorInner::NoModuleName => {
+            "a module must declare its name with `#define_import_path` before it can be imported"
+        }
+        ComposerErrorInner::DecorationInSource(_) => {
+            "naga_oil renames imported items using `X_naga_oil_mod_X`, so identifiers in the source can't contain it; rename them"
+        }
+        ComposerErrorInner::GlslInvalidVersion(_) => {
+            "GLSL modules must declare `#version 440` or `#version 450`"
+        }
+        ComposerErrorInner::OverrideNotVirtual { .. } => {
+            "only functions declared `virtual` can be overridden; add `virtual` to the function being overridden"
+        }
+        ComposerErrorInner::RedirectError(_) => {
+            "an `override fn` must name an existing function of an imported module, such as `override fn module::function`"
+        }
+        ComposerErrorInner::InvalidIdentifier { .. } => {
+            "naga would rename this identifier when writing the module back out, for example because it is a reserved word or contains `__`; choose another name"
+        }
+        ComposerErrorInner::InvalidShaderDefDefinitionValue { .. } => {
+            "a `#define` value must be `true`, `false` or an integer"
+        }
+        ComposerErrorInner::DefineInModule(_) => {
+            "move the `#define` into the main shader, or pass the def with `--defs` instead"
+        }
+        ComposerErrorInner::WgslParseError(e) if e.message().starts_with("redefinition of") => {
+            "two items have the same name; if they come from different files, two modules may share a `#define_import_path`, which `--isolate` reports"
+        }
+        _ => return None,
+    })
+}
+
+/// A suggested fix for an import that isn't among the scanned modules.
+pub const NOT_FOUND_HINT: &str = "no scanned module declares this name with `#define_import_path`; check that the file defining it is under an `--include` path and that the names match exactly";
+
+/// The number of bits naga_oil uses for the offset within a module's source in spans; the module index is stored above them.
+pub const SPAN_SHIFT: u32 = 21;
+
+/// The file and position of the primary span of a composer error, where one is available.
+fn composer_error_location(e: &ComposerError, composer: &Composer) -> Option<Location> {
+    let source_offset = e.source.offset();
+    let map_span =
+        |range: Range<usize>| (range.start & ((1 << SPAN_SHIFT) - 1)).saturating_sub(source_offset);
+
+    let offset = match &e.inner {
+        ComposerErrorInner::DecorationInSource(range) => Some(range.start),
+        ComposerErrorInner::HeaderValidationError(v)
+        | ComposerErrorInner::ShaderValidationError(v) => v
+            .spans()
+            .next()
+            .and_then(|(span, _)| span.to_range())
+            .map(map_span),
+        ComposerErrorInner::WgslParseError(e) => e
+            .labels()
+            .next()
+            .and_then(|(span, _)| span.to_range())
+            .map(map_span),
+        ComposerErrorInner::GlslParseError(e) => {
+            e.first().and_then(|e| e.meta.to_range()).map(map_span)
+        }
+        ComposerErrorInner::InvalidIdentifier { at, .. } => at.to_range().map(map_span),
+        ComposerErrorInner::ImportNotFound(_, pos)
+        | ComposerErrorInner::ImportParseError(_, pos)
+        | ComposerErrorInner::NotEnoughEndIfs(pos)
+        | ComposerErrorInner::TooManyEndIfs(pos)
+        | ComposerErrorInner::ElseWithoutCondition(pos)
+        | ComposerErrorInner::UnknownShaderDef { pos, .. }
+        | ComposerErrorInner::UnknownShaderDefOperator { pos, .. }
+        | ComposerErrorInner::InvalidShaderDefComparisonValue { pos, .. }
+        | ComposerErrorInner::OverrideNotVirtual { pos, .. }
+        | ComposerErrorInner::GlslInvalidVersion(pos)
+        | ComposerErrorInner::DefineInModule(pos)
+        | ComposerErrorInner::InvalidShaderDefDefinitionValue { pos, .. } => Some(*pos),
+        _ => None,
+    };
+
+    let file = e.source.path(composer).clone();
+    let Some(offset) = offset else {
+        return Some(Location::file(file));
+    };
+    let source = e.source.source(composer);
+    Some(Location::at_offset(file, &source, offset))
+}
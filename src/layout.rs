@@ -0,0 +1,164 @@
+//! Checking the composed module's resource bindings against an expected layout, for `--expect-bindings`.
+
+use std::{collections::BTreeMap, path::Path};
+
+use naga::{AddressSpace, ImageClass, StorageAccess, TypeInner};
+use naga_oil::compose::Composer;
+
+use crate::error::{self, Error};
+
+/// The separator naga_oil places between an item's name and its encoded module name.
+const DECORATION: &str = "X_naga_oil_mod_X";
+
+/// A binding read from the layout file.
+struct ExpectedBinding {
+    name: Option<String>,
+    kind: Option<String>,
+}
+
+/// Compare the bindings of `module` with those listed in the JSON file at `path`, failing with a list
+/// of every missing, extra or mismatched binding.
+///
+/// The file holds an array of `{ "group", "binding", "name"?, "type"? }` objects. A `name` may be
+/// qualified with its module (`module::item`), and otherwise matches the item name from any module.
+pub fn check_expected_bindings(module: &naga::Module, path: &Path) -> error::Result<()> {
+    let expected = read_layout(path)?;
+    let actual: BTreeMap<_, _> = module
+        .global_variables
+        .iter()
+        .filter_map(|(_, var)| {
+            let binding = var.binding.as_ref()?;
+            Some(((binding.group, binding.binding), var))
+        })
+        .collect();
+
+    let mut problems = Vec::new();
+    for (&(group, binding), expected) in expected.iter() {
+        let Some(var) = actual.get(&(group, binding)) else {
+            problems.push(format!("missing @group({group}) @binding({binding})"));
+            continue;
+        };
+        let name = var.name.as_deref().unwrap_or_default();
+        if let Some(expected_name) = &expected.name {
+            let matches = match expected_name.rsplit_once("::") {
+                Some((module_name, item)) => {
+                    name == Composer::decorated_name(Some(module_name), item)
+                }
+                None => name.split(DECORATION).next() == Some(expected_name.as_str()),
+            };
+            if !matches {
+                problems.push(format!(
+                    "@group({group}) @binding({binding}) is `{}`, expected `{expected_name}`",
+                    display_name(name)
+                ));
+            }
+        }
+        let kind = binding_kind(module, var.space, var.ty);
+        if let Some(expected_kind) = &expected.kind {
+            if kind != expected_kind {
+                problems.push(format!(
+                    "@group({group}) @binding({binding}) `{}` is a {kind} binding, expected {expected_kind}",
+                    display_name(name)
+                ));
+            }
+        }
+    }
+    for (&(group, binding), var) in actual.iter() {
+        if !expected.contains_key(&(group, binding)) {
+            let name = var.name.as_deref().unwrap_or_default();
+            problems.push(format!(
+                "unexpected @group({group}) @binding({binding}) `{}` ({})",
+                display_name(name),
+                binding_kind(module, var.space, var.ty)
+            ));
+        }
+    }
+
+    if problems.is_empty() {
+        return Ok(());
+    }
+    Err(Error::compose(format!(
+        "bindings don't match `{}`:\n  {}",
+        path.display(),
+        problems.join("\n  ")
+    )))
+}
+
+fn read_layout(path: &Path) -> error::Result<BTreeMap<(u32, u32), ExpectedBinding>> {
+    let contents = std::fs::read_to_string(path).map_err(|e| {
+        Error::io(format!(
+            "failed to read binding layout `{}`: {e}",
+            path.display()
+        ))
+    })?;
+    let invalid = |reason: &str| {
+        Error::args(format!(
+            "invalid binding layout `{}`: {reason}",
+            path.display()
+        ))
+    };
+    let json: serde_json::Value =
+        serde_json::from_str(&contents).map_err(|e| invalid(&e.to_string()))?;
+    let entries = json
+        .as_array()
+        .ok_or_else(|| invalid("expected an array of bindings"))?;
+
+    let mut layout = BTreeMap::new();
+    for entry in entries {
+        let index = |field| {
+            entry
+                .get(field)
+                .and_then(serde_json::Value::as_u64)
+                .and_then(|value| u32::try_from(value).ok())
+                .ok_or_else(|| invalid(&format!("every binding needs a numeric `{field}`")))
+        };
+        let text = |field| {
+            entry
+                .get(field)
+                .and_then(serde_json::Value::as_str)
+                .map(ToOwned::to_owned)
+        };
+        let key = (index("group")?, index("binding")?);
+        let binding = ExpectedBinding {
+            name: text("name"),
+            kind: text("type"),
+        };
+        if layout.insert(key, binding).is_some() {
+            return Err(invalid(&format!(
+                "@group({}) @binding({}) is listed more than once",
+                key.0, key.1
+            )));
+        }
+    }
+    Ok(layout)
+}
+
+/// The kind of resource a global is bound to: `uniform`, `storage`, `read-only-storage`, `texture`,
+/// `storage-texture`, `sampler` or `comparison-sampler`. Binding arrays have the kind of their elements.
+fn binding_kind(
+    module: &naga::Module,
+    space: AddressSpace,
+    ty: naga::Handle<naga::Type>,
+) -> &'static str {
+    match space {
+        AddressSpace::Uniform => "uniform",
+        AddressSpace::Storage { access } if access.contains(StorageAccess::STORE) => "storage",
+        AddressSpace::Storage { .. } => "read-only-storage",
+        _ => match module.types[ty].inner {
+            TypeInner::BindingArray { base, .. } => binding_kind(module, space, base),
+            TypeInner::Image {
+                class: ImageClass::Storage { .. },
+                ..
+            } => "storage-texture",
+            TypeInner::Image { .. } => "texture",
+            TypeInner::Sampler { comparison: true } => "comparison-sampler",
+            TypeInner::Sampler { comparison: false } => "sampler",
+            _ => "other",
+        },
+    }
+}
+
+/// An item name without naga_oil's module decoration, for messages.
+fn display_name(name: &str) -> &str {
+    name.split(DECORATION).next().unwrap_or(name)
+}
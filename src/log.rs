@@ -0,0 +1,279 @@
+//! Centralized reporting of progress messages and warnings to stderr.
+
+use std::{
+    fmt::Display,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Mutex, OnceLock,
+    },
+    time::Instant,
+};
+
+static WARNINGS: AtomicUsize = AtomicUsize::new(0);
+static ERRORS: AtomicUsize = AtomicUsize::new(0);
+static COMPILED: AtomicUsize = AtomicUsize::new(0);
+static COLOR: AtomicBool = AtomicBool::new(true);
+static DIAGNOSTICS: Mutex<Vec<Diagnostic>> = Mutex::new(Vec::new());
+static PROGRESS_ACTIVE: AtomicBool = AtomicBool::new(false);
+static JSON: AtomicBool = AtomicBool::new(false);
+/// When [`set_json`] was called, which `elapsed_ms` in JSON lines is measured from.
+static START: OnceLock<Instant> = OnceLock::new();
+/// Lines held back by `--quiet-success`, or `None` when output is written immediately.
+static BUFFER: Mutex<Option<Vec<String>>> = Mutex::new(None);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+impl Severity {
+    pub fn name(self) -> &'static str {
+        match self {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        }
+    }
+}
+
+/// A position in a source file. Line and column are 1-based.
+#[derive(Debug, Clone)]
+pub struct Location {
+    pub file: String,
+    pub line: Option<usize>,
+    pub column: Option<usize>,
+}
+
+impl Location {
+    pub fn file(file: impl Display) -> Self {
+        Self {
+            file: file.to_string(),
+            line: None,
+            column: None,
+        }
+    }
+
+    /// The location of byte `offset` within `source`.
+    pub fn at_offset(file: impl Display, source: &str, offset: usize) -> Self {
+        let prefix = source.get(..offset.min(source.len())).unwrap_or(source);
+        let line_start = prefix.rfind('\n').map_or(0, |pos| pos + 1);
+        Self {
+            file: file.to_string(),
+            line: Some(prefix.matches('\n').count() + 1),
+            column: Some(prefix[line_start..].chars().count() + 1),
+        }
+    }
+}
+
+/// A warning or error recorded during the run, for `--diagnostics-out`.
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    pub location: Option<Location>,
+}
+
+impl Diagnostic {
+    /// The diagnostic as a line for `--log-format json`, a `diagnostic` event with its severity as
+    /// the `level`.
+    pub fn to_json_line(&self) -> String {
+        let mut json = self.to_json();
+        json["level"] = json["severity"].take();
+        json["event"] = "diagnostic".into();
+        json.as_object_mut().unwrap().remove("severity");
+        json_line(json)
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        let location = self.location.as_ref();
+        serde_json::json!({
+            "severity": self.severity.name(),
+            "message": self.message,
+            "file": location.map(|location| &location.file),
+            "line": location.and_then(|location| location.line),
+            "column": location.and_then(|location| location.column),
+        })
+    }
+}
+
+/// Choose whether diagnostics may contain ANSI color codes.
+pub fn set_color(enabled: bool) {
+    COLOR.store(enabled, Ordering::Relaxed);
+}
+
+/// Prepare rendered diagnostic text for stderr, removing ANSI escape sequences if color is disabled.
+pub fn colorize(text: &str) -> String {
+    if COLOR.load(Ordering::Relaxed) {
+        return text.to_owned();
+    }
+    let mut plain = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            // skip a control sequence: `ESC [`, parameters, then a final byte in `@`..=`~`
+            if chars.next() == Some('[') {
+                for c in chars.by_ref() {
+                    if ('@'..='~').contains(&c) {
+                        break;
+                    }
+                }
+            }
+        } else {
+            plain.push(c);
+        }
+    }
+    plain
+}
+
+/// Write each log line to stderr as a JSON object rather than as text.
+pub fn set_json(enabled: bool) {
+    START.get_or_init(Instant::now);
+    JSON.store(enabled, Ordering::Relaxed);
+}
+
+/// Whether log lines are written as JSON.
+pub fn json() -> bool {
+    JSON.load(Ordering::Relaxed)
+}
+
+/// Hold back all output until [`flush`] is called, so that a successful run prints nothing.
+pub fn buffer() {
+    *BUFFER.lock().unwrap() = Some(Vec::new());
+}
+
+/// Write any held back output and stop buffering. Called before reporting an error.
+pub fn flush() {
+    for line in BUFFER.lock().unwrap().take().unwrap_or_default() {
+        eprintln!("{line}");
+    }
+}
+
+fn emit(line: String) {
+    match BUFFER.lock().unwrap().as_mut() {
+        Some(buffer) => buffer.push(line),
+        None => {
+            clear_progress();
+            eprintln!("{line}");
+        }
+    }
+}
+
+/// Add the time since the start of the run to a JSON log line.
+fn json_line(mut json: serde_json::Value) -> String {
+    let elapsed = START.get_or_init(Instant::now).elapsed();
+    json["elapsed_ms"] = (elapsed.as_millis() as u64).into();
+    json.to_string()
+}
+
+/// Report progress information.
+pub fn info(message: impl Display) {
+    if json() {
+        emit(json_line(
+            serde_json::json!({ "level": "info", "message": message.to_string() }),
+        ));
+    } else {
+        emit(message.to_string());
+    }
+}
+
+/// Report a step of the run. As text only the message is written, while a JSON line also names
+/// the `event` and carries `fields` (e.g. the `module` concerned), so that log aggregators needn't
+/// parse the message.
+pub fn event(event: &str, fields: serde_json::Value, message: impl Display) {
+    if !json() {
+        return emit(message.to_string());
+    }
+    let mut json = serde_json::json!({ "level": "info", "event": event });
+    if let serde_json::Value::Object(fields) = fields {
+        json.as_object_mut().unwrap().extend(fields);
+    }
+    json["message"] = message.to_string().into();
+    emit(json_line(json));
+}
+
+/// Show a transient status line, replacing any previous one. Only call this when stderr is a terminal.
+pub fn progress(message: impl Display) {
+    if json() || BUFFER.lock().unwrap().is_some() {
+        return;
+    }
+    PROGRESS_ACTIVE.store(true, Ordering::Relaxed);
+    eprint!("\r\x1b[2K{message}");
+}
+
+/// Remove the status line shown by [`progress`], if any.
+pub fn clear_progress() {
+    if PROGRESS_ACTIVE.swap(false, Ordering::Relaxed) {
+        eprint!("\r\x1b[2K");
+    }
+}
+
+/// Report a warning, optionally relating to a specific file or position. Warnings are counted so
+/// that `--deny-warnings` can fail the run.
+pub fn warn_at(location: Option<Location>, message: impl Display) {
+    WARNINGS.fetch_add(1, Ordering::Relaxed);
+    let diagnostic = Diagnostic {
+        severity: Severity::Warning,
+        message: message.to_string(),
+        location,
+    };
+    if json() {
+        emit(diagnostic.to_json_line());
+    } else {
+        emit(format!("warning: {message}"));
+    }
+    record(diagnostic);
+}
+
+/// Record a diagnostic without printing it. Errors are counted for the end of run summary.
+pub fn record(diagnostic: Diagnostic) {
+    if diagnostic.severity == Severity::Error {
+        ERRORS.fetch_add(1, Ordering::Relaxed);
+    }
+    DIAGNOSTICS.lock().unwrap().push(diagnostic);
+}
+
+/// All diagnostics recorded so far.
+pub fn diagnostics() -> Vec<Diagnostic> {
+    DIAGNOSTICS.lock().unwrap().clone()
+}
+
+/// The number of warnings reported so far.
+pub fn warning_count() -> usize {
+    WARNINGS.load(Ordering::Relaxed)
+}
+
+/// The number of errors recorded so far.
+pub fn error_count() -> usize {
+    ERRORS.load(Ordering::Relaxed)
+}
+
+/// Count a shader whose output has been written.
+pub fn compiled() {
+    COMPILED.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Report the number of shaders compiled and warnings and errors reported, if anything happened.
+pub fn summary() {
+    let compiled = COMPILED.load(Ordering::Relaxed);
+    let (warnings, errors) = (warning_count(), error_count());
+    if compiled + warnings + errors > 0 {
+        event(
+            "summary",
+            serde_json::json!({ "compiled": compiled, "warnings": warnings, "errors": errors }),
+            format!(
+                "compiled {compiled} shader{}, {warnings} warning{}, {errors} error{}",
+                plural(compiled),
+                plural(warnings),
+                plural(errors)
+            ),
+        );
+    }
+}
+
+fn plural(count: usize) -> &'static str {
+    if count == 1 {
+        ""
+    } else {
+        "s"
+    }
+}
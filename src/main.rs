@@ -1,16 +1,35 @@
+mod capabilities;
+mod diff;
+mod error;
+mod layout;
+mod log;
+mod scan;
+mod sort;
+mod spv;
+mod visit;
+
 use std::{
-    collections::{HashMap, HashSet},
-    io::{stdout, Write},
+    collections::{BTreeMap, BTreeSet, HashMap},
+    io::{stdout, IsTerminal, Write},
     path::{Path, PathBuf},
     process::exit,
-    str::FromStr,
 };
 
-use clap::Parser;
-use naga::valid::Capabilities;
+use clap::{parser::ValueSource, CommandFactory, FromArgMatches, Parser};
+use error::{Error, ErrorKind};
+use log::{Diagnostic, Location, Severity};
+use naga::{
+    proc::{BoundsCheckPolicies, BoundsCheckPolicy},
+    valid::Capabilities,
+    EntryPoint, ShaderStage,
+};
 use naga_oil::compose::{
-    ComposableModuleDescriptor, Composer, ComposerErrorInner, NagaModuleDescriptor, ShaderDefValue,
-    ShaderLanguage, ShaderType,
+    ComposableModuleDescriptor, Composer, NagaModuleDescriptor, ShaderDefValue, ShaderLanguage,
+    ShaderType,
+};
+use scan::{
+    front_matter_shader_type, import_names, inline_glsl_includes, normalize_path,
+    resolve_path_imports, shader_type, IncludedModule,
 };
 
 #[derive(Parser)]
@@ -20,6 +39,19 @@ use naga_oil::compose::{
     about = "Construct standalone shaders from modules and definitions using naga_oil (https://github.com/bevyengine/naga_oil)"
 )]
 #[command(next_line_help = true)]
+#[command(
+    after_long_help = "Arguments of the form `@file` are replaced with the newline-separated arguments contained in `file`.
+
+Exit codes:
+  0  success
+  1  composition or validation error
+  2  I/O error
+  3  argument or input parse error
+  4  unresolved import
+  5  backend write error
+  6  composition timed out"
+)]
+#[derive(Clone)]
 pub struct Cli {
     /// Disable checking invariance of variable names when regenerating wgsl or gsls from naga modules.
     /// It may be necessary to disable naga_oil's validation for features which are unsupported by naga::back::{glsl, wgsl}.
@@ -35,6 +67,17 @@ pub struct Cli {
     #[arg(short, long, env = "NAGA_OIL_INCLUDE_PATH", verbatim_doc_comment)]
     include: Vec<String>,
 
+    /// Read additional include paths from a file, one per line, each handled like an `--include` argument.
+    /// Blank lines and lines starting with `#` are ignored. This argument may be repeated
+    #[arg(long, value_name = "FILE", verbatim_doc_comment)]
+    include_from: Vec<PathBuf>,
+
+    /// An include path whose modules without a `#define_import_path` are named with a prefix, given as `PREFIX:DIR`.
+    /// A module at `DIR/sub/mod.wgsl` is then imported as `#import "PREFIX::sub/mod.wgsl"`, so that libraries with the same
+    /// file names can be combined. This argument may be repeated
+    #[arg(long, value_name = "PREFIX:DIR", verbatim_doc_comment, value_parser = parse_prefixed_include)]
+    include_prefixed: Vec<(String, PathBuf)>,
+
     /// Shader definitions, specified as semicolon-separated names or name=value pairs.
     ///
     /// Raw names will be defined in the shader compilation with value Bool(true), useful for `#ifdef` and `#if def == true` directives.
@@ -42,17 +85,86 @@ pub struct Cli {
     /// - `true` or `false` for a bool value (e.g. `--defs MY_SETTING`)
     /// - a numeric literal for an i32 value (e.g. `--defs MY_NUMBER=-123`)
     /// - a non-negative numeric literal with a trailing `u` for a u32 value (e.g. `-d MY_NUMBER=123u`)
+    ///
+    /// The type can also be given explicitly with `name:type=value`, where type is `bool`, `i32` or `u32` (e.g. `-d MY_NUMBER:u32=123`).
     /// This argument may be repeated to specify multiple defs, or split with semicolons (`-d ONE;TWO=123`).
+    /// If a def is given more than once, the last value wins.
     #[arg(short, long, env = "NAGA_OIL_DEFS", verbatim_doc_comment)]
     defs: Vec<String>,
 
-    /// Additional shader definitions, added to or overwriting definitions provided with --defs. potentially useful for supplying additional defs when a base set is supplied via `--defs` or `NAGA_OIL_DEFS`
+    /// Additional shader definitions, applied after --defs so they are added to or overwrite definitions provided with --defs. potentially useful for supplying additional defs when a base set is supplied via `--defs` or `NAGA_OIL_DEFS`
     #[arg(short, long, env = "NAGA_OIL_ADDITIONAL_DEFS", verbatim_doc_comment)]
     additional_defs: Vec<String>,
 
+    /// Run a shell command and read defs from its output, in the same format as `--defs` (one or more per line).
+    /// These are applied after `--defs` and before `--additional-defs`. A non-zero exit status is an error
+    #[arg(long, value_name = "COMMAND", verbatim_doc_comment)]
+    defs_cmd: Option<String>,
+
+    /// Pipe the source of each module and of the main shader through a shell command before composition, using its output as the source.
+    /// A non-zero exit status is an error
+    #[arg(long, value_name = "COMMAND", verbatim_doc_comment)]
+    transform_cmd: Option<String>,
+
+    /// Warn whenever a def replaces a value given earlier, within or across `--defs` and `--additional-defs`
+    #[arg(long, action = clap::ArgAction::SetTrue)]
+    warn_def_override: bool,
+
+    /// Fail unless a def named NAME is given, by `--defs`, `--additional-defs`, `--defs-cmd` or their environment variables.
+    /// This argument may be repeated to require several defs
+    #[arg(long, value_name = "NAME", verbatim_doc_comment)]
+    require_def: Vec<String>,
+
+    /// Shader language to use for scanned modules with the given extension, specified as `extension=language` where language is `wgsl` or `glsl`.
+    /// This allows library modules with unconventional extensions to be included, e.g. `--module-lang glsl=glsl` or `--module-lang wgsl.in=wgsl`, and takes precedence over the built-in extensions.
+    /// This argument may be repeated, or split with semicolons.
+    #[arg(long, verbatim_doc_comment, value_delimiter = ';', value_parser = parse_module_lang)]
+    module_lang: Vec<(String, ShaderLanguage)>,
+
+    /// Shader language, `wgsl` or `glsl`, of scanned files without an extension, which are otherwise skipped.
+    /// Use `--module-lang` for files with unrecognized extensions
+    #[arg(long, value_name = "LANGUAGE", verbatim_doc_comment, value_parser = parse_language)]
+    default_include_lang: Option<ShaderLanguage>,
+
     /// The shader containing the target entry point
+    #[arg(
+        required_unless_present_any = ["compile_tree", "inspect", "unpack", "from_naga"],
+        default_value = ".",
+        hide_default_value = true
+    )]
     shader: PathBuf,
 
+    /// Compile every shader with an entry point under DIR, rather than a single shader, writing the outputs to the
+    /// directory given by `--output` with the same structure. Each output is named after its input with the output
+    /// extension added, unless the input already has it (e.g. `a.vert` is written to `a.vert.wgsl`). All entry points
+    /// of each shader are written, and files without entry points are treated as library modules and skipped
+    #[arg(long, value_name = "DIR", requires = "output", conflicts_with_all = ["entry_index", "stage", "strip_entry_points"])]
+    compile_tree: Option<PathBuf>,
+
+    /// Read the shader and its modules from a single file, split into files by `// === path ===` marker lines.
+    /// The shader to compile is marked with `// === path === entry`, or is otherwise the first file. The other files are
+    /// available for import like scanned modules, and include paths are only scanned if given explicitly
+    #[arg(long, value_name = "FILE", verbatim_doc_comment, conflicts_with_all = ["shader", "compile_tree"])]
+    unpack: Option<PathBuf>,
+
+    /// Read a module previously written as NAGA output, with or without `--embed-source`, and validate and write it
+    /// in the chosen output format. No modules are scanned or composed, so defs and imports have no effect
+    #[arg(
+        long,
+        value_name = "FILE",
+        verbatim_doc_comment,
+        conflicts_with_all = ["shader", "compile_tree", "unpack", "source_map", "embed_source", "sidecar", "spv_debug"]
+    )]
+    from_naga: Option<PathBuf>,
+
+    /// The main shader's source, when it is read from the `--unpack` file rather than from `shader`
+    #[arg(skip)]
+    unpacked_source: Option<String>,
+
+    /// Print the module name, imports, declared defs and referenced defs of FILE as JSON, then exit
+    #[arg(long, value_name = "FILE")]
+    inspect: Option<PathBuf>,
+
     /// Output format. one of `WGSL`, `GLSL`, `NAGA` (serde_json serialized), `SPV`. If not specified, then if an ouptut filename is specified, attempts to determine the output based on the extension:
     ///
     /// `wgsl` => WGSL
@@ -66,9 +178,442 @@ pub struct Cli {
     #[arg(short, long, env = "NAGA_OIL_FORMAT", verbatim_doc_comment, value_parser = parse_format)]
     format: Option<OutputFormat>,
 
-    /// Output file. if unspecified, output is written to stdout
+    /// Output file. if unspecified or `-`, output is written to stdout
     #[arg(short, long, env = "NAGA_OIL_OUTPUT")]
     output: Option<PathBuf>,
+
+    /// Write WGSL output to this file. Together with `--output-glsl`, `--output-spv` and `--output-naga`, this writes several formats
+    /// from one invocation, in place of `--output` and `--format`
+    #[arg(long, value_name = "PATH", verbatim_doc_comment, conflicts_with_all = ["output", "format", "compile_tree"])]
+    output_wgsl: Option<PathBuf>,
+
+    /// Write GLSL output to this file
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["output", "format", "compile_tree"])]
+    output_glsl: Option<PathBuf>,
+
+    /// Write SPIR-V output to this file
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["output", "format", "compile_tree"])]
+    output_spv: Option<PathBuf>,
+
+    /// Write NAGA output to this file
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["output", "format", "compile_tree"])]
+    output_naga: Option<PathBuf>,
+
+    /// The entry point to use for GLSL and SPV output. Defaults to the entry point matching `--stage`, or the only entry point.
+    /// If several entry points match, the entry point must be chosen explicitly.
+    /// A default can be set with `NAGA_OIL_ENTRY_POINT`, which is ignored if the entry point is selected with another option such as `--stage`
+    #[arg(short, long, env = "NAGA_OIL_ENTRY_POINT")]
+    entry_point: Option<String>,
+
+    /// Select the entry point for GLSL and SPV output by its (zero-based) position in the composed module instead of by name
+    #[arg(long, value_name = "N", conflicts_with = "stage")]
+    entry_index: Option<usize>,
+
+    /// The stage of the entry point to use for GLSL and SPV output, one of `vertex`, `fragment` or `compute`.
+    /// If neither this nor `--entry-point` is specified for GLSL output to a `.vert`, `.frag` or `.comp` file, the stage is taken from the extension.
+    #[arg(short, long, verbatim_doc_comment, value_parser = parse_stage)]
+    stage: Option<ShaderStage>,
+
+    /// Output all entry points rather than a single one.
+    /// For GLSL output a separate file is written for each entry point, named `<output stem>.<entry point>.<vert|frag|comp>`.
+    #[arg(long, verbatim_doc_comment, action = clap::ArgAction::SetTrue)]
+    all_entry_points: bool,
+
+    /// GLSL version to target for GLSL output, e.g. `450`, `330` or `es-300`. Defaults to `450`.
+    /// For GLSL ES versions, the shader is validated against the reduced feature set that GLSL ES can express.
+    /// Shaders with `@location`s between the vertex and fragment stages need `410` or `es-310` or later.
+    #[arg(long, verbatim_doc_comment, default_value = "450", value_parser = parse_glsl_version)]
+    glsl_version: naga::back::glsl::Version,
+
+    /// Target Vulkan-flavoured GLSL, as consumed by `glslang`: a shortcut for `--glsl-version 460`.
+    /// naga 0.14 has no other Vulkan-specific GLSL writer options, so bindings are not given explicit `binding` qualifiers.
+    /// Conflicts with `--glsl-version`, and so with GLSL ES (`es-*`) versions and `--glsl-webgl`.
+    #[arg(long, verbatim_doc_comment, conflicts_with = "glsl_version", action = clap::ArgAction::SetTrue)]
+    glsl_vulkan: bool,
+
+    /// Handle `#include "file"` directives in GLSL modules and shaders by inlining the named scanned file, resolved relative to the
+    /// including file or the working directory. Each file is included at most once.
+    /// Included files must be found by scanning, so files with a `.glsl` extension also need `--module-lang glsl=glsl`
+    #[arg(long, verbatim_doc_comment, action = clap::ArgAction::SetTrue)]
+    glsl_includes: bool,
+
+    /// Target WebGL rather than plain GLSL ES, which changes the extensions, guards and builtins naga emits.
+    /// Requires a GLSL ES `--glsl-version`, e.g. `es-300` for WebGL 2
+    #[arg(long, verbatim_doc_comment, conflicts_with = "glsl_vulkan", action = clap::ArgAction::SetTrue)]
+    glsl_webgl: bool,
+
+    /// Prepend a comment block to WGSL output listing the main shader, the contributing module files and the defs used
+    #[arg(long, action = clap::ArgAction::SetTrue)]
+    source_map: bool,
+
+    /// Write output files to a temporary file alongside the target and rename it into place once complete,
+    /// so that concurrent readers never see a partially written file. On failure any existing output is left intact.
+    #[arg(long, verbatim_doc_comment, env = "NAGA_OIL_ATOMIC_OUTPUT", action = clap::ArgAction::SetTrue)]
+    atomic_output: bool,
+
+    /// Read additional modules from stdin as a JSON object mapping module names to sources, e.g. `{ "my::module": "fn f() {}" }`.
+    /// A module may instead be given as `{ "source": ..., "language": "wgsl" | "glsl" }`; otherwise its language is guessed from its source.
+    /// These modules take precedence over modules with the same name found in the include paths.
+    #[arg(long, verbatim_doc_comment, action = clap::ArgAction::SetTrue)]
+    modules_stdin: bool,
+
+    /// Compare the generated output with a previously generated artifact, printing a unified diff to stderr for WGSL and GLSL output,
+    /// or whether the output changed for other formats. Output is written as usual
+    #[arg(
+        long,
+        value_name = "PATH",
+        verbatim_doc_comment,
+        conflicts_with = "compile_tree"
+    )]
+    diff_against: Option<PathBuf>,
+
+    /// Fail instead of writing an artifact larger than N bytes, for any output format
+    #[arg(long, value_name = "N")]
+    max_output_bytes: Option<usize>,
+
+    /// After writing output, print its size in bytes and a short content hash to stderr
+    #[arg(long, action = clap::ArgAction::SetTrue)]
+    print_summary: bool,
+
+    /// Write the module graph to a JSON file: `nodes` for the main shader and each scanned module (with `name`, `path`,
+    /// `language`, and whether it is the `main` shader or `used` by it) and an `edges` entry `{ "from", "to" }` for each import
+    #[arg(long, value_name = "PATH", verbatim_doc_comment)]
+    graph_json: Option<PathBuf>,
+
+    /// Record the file each imported module resolves to in a JSON lockfile, and fail if a later run resolves a
+    /// recorded module to a different file. Modules not yet in the lockfile are added to it.
+    /// Paths are recorded relative to the lockfile's directory, with `/` separators, so the lockfile can be checked in
+    #[arg(long, value_name = "PATH")]
+    lockfile: Option<PathBuf>,
+
+    /// Find every import that can't be resolved, across the whole import graph, and report them all together
+    /// rather than stopping at the first
+    #[arg(long, verbatim_doc_comment, action = clap::ArgAction::SetTrue)]
+    collect_missing: bool,
+
+    /// Log each decision made while resolving imports: modules found, deferred until their imports are added, added or
+    /// skipped as already present, along with the chain of imports that led to them
+    #[arg(long, verbatim_doc_comment, action = clap::ArgAction::SetTrue)]
+    explain_resolution: bool,
+
+    /// Follow common errors with a short explanation and a suggested fix
+    #[arg(long, action = clap::ArgAction::SetTrue)]
+    explain_error: bool,
+
+    /// Warn about each scanned module that the shader doesn't (directly or indirectly) import
+    #[arg(long, action = clap::ArgAction::SetTrue)]
+    warn_unused_modules: bool,
+
+    /// Only consider modules the shader transitively imports: duplicate definitions of unused modules are ignored, while duplicates of required ones are an error
+    #[arg(long, action = clap::ArgAction::SetTrue)]
+    isolate: bool,
+
+    /// Remove all entry points, writing the composed functions and types as a reusable library module.
+    /// Only valid for WGSL and NAGA output
+    #[arg(long, action = clap::ArgAction::SetTrue, conflicts_with_all = ["stage", "all_entry_points"])]
+    strip_entry_points: bool,
+
+    /// Fail if scanning the include paths finds more than N modules
+    #[arg(long, value_name = "N")]
+    max_modules: Option<usize>,
+
+    /// Limit how many directory levels below each include path, and the `--compile-tree` directory, are scanned.
+    /// `0` scans only the files directly in the directory
+    #[arg(long, value_name = "N")]
+    max_depth: Option<usize>,
+
+    /// Warn when a module's `#define_import_path` doesn't match its path relative to its include directory,
+    /// e.g. `pbr::lighting` for `pbr/lighting.wgsl`
+    #[arg(long, action = clap::ArgAction::SetTrue)]
+    check_naming: bool,
+
+    /// Leading path segment expected before the directory-derived part of module names for `--check-naming`
+    #[arg(long, value_name = "PREFIX", requires = "check_naming")]
+    naming_prefix: Option<String>,
+
+    /// On success, print a JSON object describing the written artifacts (format, path, entry point and size)
+    /// to stdout. Requires `--output`
+    #[arg(long, action = clap::ArgAction::SetTrue)]
+    json_result: bool,
+
+    /// File name used for each output when several are written (by `--all-entry-points` with GLSL output, or several `--spv-version`s).
+    /// Placeholders `{stem}`, `{entry}`, `{stage}`, `{version}` and `{ext}` are replaced with the output (or, if `--output` is a directory,
+    /// input shader) file stem, the entry point name, its stage, the SPIR-V version and the output extension.
+    /// Defaults to `{stem}.{entry}.{ext}`, or `{stem}.{entry}.spv{version}.{ext}` for multiple SPIR-V versions
+    #[arg(long, value_name = "TEMPLATE")]
+    name_template: Option<String>,
+
+    /// Skip the final validation of the composed module, so that the IR of a module naga rejects can still be
+    /// inspected. Combine with `--no-validation` to also skip validation during composition. Only valid for WGSL and NAGA output
+    #[arg(long, action = clap::ArgAction::SetTrue)]
+    skip_final_validation: bool,
+
+    /// Print a hash of the main shader, the defs and the sources of the modules it imports, then exit without composing.
+    /// The hash changes whenever any of these inputs change, so it can be used as a cache key
+    #[arg(long, verbatim_doc_comment, action = clap::ArgAction::SetTrue)]
+    graph_hash: bool,
+
+    /// Print each shader def referenced by the main shader or any scanned module, with the modules that reference it, then exit
+    #[arg(long, action = clap::ArgAction::SetTrue)]
+    list_defs: bool,
+
+    /// Set environment variables (such as `NAGA_OIL_DEFS` or `NAGA_OIL_INCLUDE_PATH`) from `KEY=VALUE` lines in an env file.
+    /// Uses `.env`, if present, when no path is given. Explicit flags and variables already in the environment take precedence.
+    /// A following argument is taken as the path, so `--dotenv` without a path goes after the shader or before another flag
+    #[arg(long, value_name = "PATH", num_args = 0..=1)]
+    dotenv: Option<Option<PathBuf>>,
+
+    /// Wrap NAGA output in a JSON object that also records the main shader's path and source, the defs used and the
+    /// modules added, with the module itself under `module`
+    #[arg(long, action = clap::ArgAction::SetTrue)]
+    embed_source: bool,
+
+    /// Order the global variables and functions of NAGA output by name, as far as calls between functions allow, so
+    /// that the output diffs cleanly when modules change. Other parts of the module keep naga's order, so this is best
+    /// effort
+    #[arg(long, verbatim_doc_comment, action = clap::ArgAction::SetTrue)]
+    sort_ir: bool,
+
+    /// Also write `<output>.defs.json` next to each output file, recording the main shader, the defs used and the
+    /// modules added, so that a checked in output documents how to regenerate it
+    #[arg(long, verbatim_doc_comment, action = clap::ArgAction::SetTrue)]
+    sidecar: bool,
+
+    /// Show a running count of scanned files and discovered modules while scanning large include trees (only when stderr is a terminal)
+    #[arg(long, action = clap::ArgAction::SetTrue)]
+    progress: bool,
+
+    /// Allow quoted imports to be resolved relative to the importing file, e.g. `#import "../shared/util.wgsl"`.
+    /// Imports are only resolved this way if no module has the literal import name.
+    #[arg(long, verbatim_doc_comment, action = clap::ArgAction::SetTrue)]
+    relative_imports: bool,
+
+    /// Bounds check policy for all access classes in GLSL and SPV output, one of `restrict`, `read-zero-skip-write` or `unchecked`.
+    /// Defaults to naga's default policies. The `--bounds-*` options override this for individual access classes.
+    #[arg(long, verbatim_doc_comment, value_parser = parse_bounds_check_policy)]
+    bounds_check: Option<BoundsCheckPolicy>,
+
+    /// Bounds check policy for indexing arrays, vectors and matrices
+    #[arg(long, value_parser = parse_bounds_check_policy)]
+    bounds_index: Option<BoundsCheckPolicy>,
+
+    /// Bounds check policy for accesses to uniform and storage buffers
+    #[arg(long, value_parser = parse_bounds_check_policy)]
+    bounds_buffer: Option<BoundsCheckPolicy>,
+
+    /// Bounds check policy for image loads
+    #[arg(long, value_parser = parse_bounds_check_policy)]
+    bounds_image_load: Option<BoundsCheckPolicy>,
+
+    /// Bounds check policy for image stores
+    #[arg(long, value_parser = parse_bounds_check_policy)]
+    bounds_image_store: Option<BoundsCheckPolicy>,
+
+    /// Bounds check policy for indexing binding arrays
+    #[arg(long, value_parser = parse_bounds_check_policy)]
+    bounds_binding_array: Option<BoundsCheckPolicy>,
+
+    /// Name of the entry point function in GLSL output, instead of `main`.
+    /// `{entry}` is replaced with the name of the entry point in the composed module.
+    /// The output then has no `main`, so it can only be linked into a program that provides one, not compiled on its own
+    #[arg(long, value_name = "NAME", verbatim_doc_comment)]
+    glsl_entry_name: Option<String>,
+
+    /// Add `#extension NAME : require` after the `#version` line of GLSL output, for extensions that naga doesn't
+    /// declare itself. Can be repeated
+    #[arg(long = "glsl-extension", value_name = "NAME", verbatim_doc_comment)]
+    glsl_extensions: Vec<String>,
+
+    /// Make vertex shaders write the point size (`gl_PointSize` / `PointSize`) in GLSL and SPIR-V output,
+    /// as required by some drivers for drawing point lists
+    #[arg(long, action = clap::ArgAction::SetTrue)]
+    force_point_size: bool,
+
+    /// Place the resource at WGSL `@group(GROUP) @binding(BINDING)` at descriptor set `SET`, binding `BINDING` in SPIR-V output,
+    /// given as `GROUP:BINDING=SET:BINDING`. Can be repeated
+    #[arg(long = "spv-binding", value_name = "MAPPING", value_parser = parse_binding_mapping)]
+    spv_bindings: Vec<(naga::ResourceBinding, naga::ResourceBinding)>,
+
+    /// SPIR-V version to target, e.g. `1.3`. Several comma-separated versions (e.g. `1.3,1.4,1.5`) write a file for each,
+    /// named according to `--name-template`
+    #[arg(long = "spv-version", value_name = "VERSION", value_delimiter = ',', value_parser = parse_spv_version)]
+    spv_versions: Vec<(u8, u8)>,
+
+    /// Include debug information in SPIR-V output: names, and the main shader's source with `OpLine` positions for its statements.
+    /// Positions are approximate when the shader has imports, and statements from imported modules are attributed to its first line
+    #[arg(long, verbatim_doc_comment, action = clap::ArgAction::SetTrue)]
+    spv_debug: bool,
+
+    /// Keep global variables and functions that the selected entry point doesn't use in SPIR-V output, rather than omitting them
+    /// as naga does by default, so that every declared binding appears. Output with `--all-entry-points` always keeps them
+    #[arg(long, verbatim_doc_comment, action = clap::ArgAction::SetTrue)]
+    spv_keep_unused: bool,
+
+    /// Declare an additional SPIR-V capability (e.g. `RayQueryKHR`) in SPIR-V output. Can be repeated
+    #[arg(long = "spv-capability", value_name = "NAME", value_parser = parse_spv_capability)]
+    spv_capabilities: Vec<naga::back::spv::Capability>,
+
+    /// Declare a SPIR-V extension (e.g. `SPV_KHR_ray_query`) in SPIR-V output. Can be repeated
+    #[arg(long = "spv-extension", value_name = "NAME")]
+    spv_extensions: Vec<String>,
+
+    /// After generating WGSL output, check that it parses and validates with naga's WGSL frontend,
+    /// failing if the generated WGSL doesn't itself compile
+    #[arg(long, verbatim_doc_comment, action = clap::ArgAction::SetTrue)]
+    roundtrip_check: bool,
+
+    /// Write all warnings and errors from the run to this file as a JSON array of objects with `severity`, `message`, `file`, `line` and `column` fields.
+    /// The file is written even when there are no diagnostics, and is replaced atomically.
+    #[arg(long, verbatim_doc_comment)]
+    diagnostics_out: Option<PathBuf>,
+
+    /// Check the composed module's resource bindings against a JSON array of `{ "group", "binding", "name", "type" }` objects,
+    /// failing with a list of missing, unexpected and mismatched bindings. `name` and `type` are optional. A `name` may be qualified
+    /// with its module, e.g. `bevy_pbr::mesh_view_bindings::view`. `type` is one of `uniform`, `storage`, `read-only-storage`,
+    /// `texture`, `storage-texture`, `sampler` or `comparison-sampler`
+    #[arg(long, value_name = "FILE", verbatim_doc_comment)]
+    expect_bindings: Option<PathBuf>,
+
+    /// Abort with exit code 6 if composing and validating the shader takes longer than this many seconds
+    #[arg(long, value_name = "SECONDS", value_parser = parse_timeout)]
+    timeout: Option<f64>,
+
+    /// Print the workgroup size of each compute entry point in the composed module, then exit without writing output
+    #[arg(long, action = clap::ArgAction::SetTrue)]
+    print_workgroup_size: bool,
+
+    /// Print the name and stage of each entry point in the composed module (with the workgroup size for compute entry points), then exit without writing output
+    #[arg(long, action = clap::ArgAction::SetTrue)]
+    list_entry_points: bool,
+
+    /// Print the validation capabilities (e.g. `FLOAT64`) needed by each entry point of the composed module on its own,
+    /// then exit without writing output
+    #[arg(long, verbatim_doc_comment, action = clap::ArgAction::SetTrue)]
+    report_capabilities_per_entry: bool,
+
+    /// Which declarations get explicit types in WGSL output: `all` or `none`.
+    /// naga always writes the type of `var` declarations, so `none` only drops the annotations on `let` bindings
+    #[arg(long, default_value = "all", verbatim_doc_comment, value_parser = parse_wgsl_explicit_types)]
+    wgsl_explicit_types: WgslExplicitTypes,
+
+    /// Re-indent WGSL and GLSL output with `tabs` or the given number of spaces per level, instead of naga's four spaces.
+    /// Only leading indentation is changed, so the lines of the output are otherwise unchanged
+    #[arg(long, value_name = "STYLE", verbatim_doc_comment, value_parser = parse_indent)]
+    indent: Option<Indent>,
+
+    /// How WGSL and GLSL output ends: `keep` leaves the backend's output as is, `add` ends it with exactly one newline and `strip` removes trailing newlines
+    #[arg(long, default_value = "keep", value_parser = parse_trailing_newline)]
+    trailing_newline: TrailingNewline,
+
+    /// Print nothing to stderr unless the run fails, in which case all the held back log lines and warnings are printed before the error
+    #[arg(long, action = clap::ArgAction::SetTrue)]
+    quiet_success: bool,
+
+    /// When to use color in diagnostics: `auto`, `always` or `never`.
+    /// With `auto`, color is forced by a non-zero `CLICOLOR_FORCE`, disabled by `NO_COLOR`, and otherwise used when stderr is a terminal
+    #[arg(long, default_value = "auto", verbatim_doc_comment, value_parser = parse_color)]
+    color: ColorChoice,
+
+    /// Format of log lines on stderr: `text`, or `json` to write each message, warning and error as a JSON object on its own line,
+    /// with `level` (`info`, `warning` or `error`), `message` and `elapsed_ms` fields, and `file`, `line` and `column` fields for warnings and errors.
+    /// Steps of the run also name their `event` (e.g. `module_found`, `module_added`, `compiling`, `output_written` or `summary`)
+    /// along with the `module` or `file` concerned
+    #[arg(long, default_value = "text", verbatim_doc_comment, value_parser = parse_log_format)]
+    log_format: LogFormat,
+
+    /// Order of the `adding module` log lines: `topo` (the order modules are added to the composer, dependencies first) or `alpha`
+    #[arg(long, default_value = "topo", value_parser = parse_log_order)]
+    log_order: LogOrder,
+
+    /// Treat inconsistencies between the inputs, such as an entry point whose stage doesn't match the shader's file extension, as errors rather than warnings
+    #[arg(long, env = "NAGA_OIL_STRICT", action = clap::ArgAction::SetTrue)]
+    strict: bool,
+
+    /// Exit with an error if any warnings were emitted, even if compilation succeeded
+    #[arg(long, env = "NAGA_OIL_DENY_WARNINGS", action = clap::ArgAction::SetTrue)]
+    deny_warnings: bool,
+}
+
+impl Cli {
+    fn glsl_options(&self) -> error::Result<naga::back::glsl::Options> {
+        let version = match (self.glsl_vulkan, self.glsl_version) {
+            (true, _) => naga::back::glsl::Version::Desktop(460),
+            (false, naga::back::glsl::Version::Embedded { version, .. }) => {
+                naga::back::glsl::Version::Embedded {
+                    version,
+                    is_webgl: self.glsl_webgl,
+                }
+            }
+            (false, version) if self.glsl_webgl => {
+                return Err(Error::args(format!(
+                    "`--glsl-webgl` requires a GLSL ES version (e.g. `--glsl-version es-300`), not {version}"
+                )))
+            }
+            (false, version) => version,
+        };
+
+        let mut writer_flags = naga::back::glsl::WriterFlags::INCLUDE_UNUSED_ITEMS;
+        writer_flags.set(
+            naga::back::glsl::WriterFlags::FORCE_POINT_SIZE,
+            self.force_point_size,
+        );
+
+        Ok(naga::back::glsl::Options {
+            version,
+            writer_flags,
+            ..Default::default()
+        })
+    }
+
+    fn wgsl_writer_flags(&self) -> naga::back::wgsl::WriterFlags {
+        match self.wgsl_explicit_types {
+            WgslExplicitTypes::All => naga::back::wgsl::WriterFlags::EXPLICIT_TYPES,
+            WgslExplicitTypes::None => naga::back::wgsl::WriterFlags::empty(),
+        }
+    }
+
+    fn spv_options(&self) -> naga::back::spv::Options<'static> {
+        let mut options = naga::back::spv::Options {
+            bounds_check_policies: self.bounds_check_policies(),
+            ..Default::default()
+        };
+        options.flags.set(
+            naga::back::spv::WriterFlags::FORCE_POINT_SIZE,
+            self.force_point_size,
+        );
+        options
+    }
+
+    fn bounds_check_policies(&self) -> BoundsCheckPolicies {
+        let default = BoundsCheckPolicies::default();
+        let policy = |specific: Option<BoundsCheckPolicy>, default: BoundsCheckPolicy| {
+            specific.or(self.bounds_check).unwrap_or(default)
+        };
+
+        BoundsCheckPolicies {
+            index: policy(self.bounds_index, default.index),
+            buffer: policy(self.bounds_buffer, default.buffer),
+            image_load: policy(self.bounds_image_load, default.image_load),
+            image_store: policy(self.bounds_image_store, default.image_store),
+            binding_array: policy(self.bounds_binding_array, default.binding_array),
+        }
+    }
+
+    /// Apply `--indent` and `--trailing-newline` to WGSL or GLSL output.
+    fn text_output(&self, text: String) -> String {
+        let text = match &self.indent {
+            Some(indent) => indent.apply(&text),
+            None => text,
+        };
+        self.trailing_newline.apply(text)
+    }
+
+    /// The output file, or `None` if output should be written to stdout.
+    fn output_path(&self) -> Option<&Path> {
+        self.output
+            .as_deref()
+            .filter(|path| path.as_os_str() != "-")
+    }
 }
 
 #[derive(Clone)]
@@ -79,6 +624,17 @@ enum OutputFormat {
     Spirv,
 }
 
+impl OutputFormat {
+    fn name(&self) -> &'static str {
+        match self {
+            OutputFormat::Wgsl => "wgsl",
+            OutputFormat::Glsl => "glsl",
+            OutputFormat::Naga => "naga",
+            OutputFormat::Spirv => "spv",
+        }
+    }
+}
+
 fn parse_format(arg: &str) -> Result<OutputFormat, clap::Error> {
     match arg.trim().to_lowercase().as_str() {
         "wgsl" => Ok(OutputFormat::Wgsl),
@@ -89,260 +645,2581 @@ fn parse_format(arg: &str) -> Result<OutputFormat, clap::Error> {
     }
 }
 
-fn gather_paths(args: &[String]) -> Vec<PathBuf> {
-    if args.is_empty() {
-        return vec![PathBuf::from_str(".").unwrap()];
+#[derive(Clone)]
+enum LogOrder {
+    Topo,
+    Alpha,
+}
+
+fn parse_log_order(arg: &str) -> Result<LogOrder, clap::Error> {
+    match arg.trim().to_lowercase().as_str() {
+        "topo" => Ok(LogOrder::Topo),
+        "alpha" => Ok(LogOrder::Alpha),
+        _ => Err(clap::Error::new(clap::error::ErrorKind::InvalidValue)),
     }
-    let mut paths = Vec::default();
-    for arg in args.iter().flat_map(|arg| arg.split(';')) {
-        paths.push(PathBuf::from_str(arg).unwrap());
+}
+
+#[derive(Clone)]
+enum LogFormat {
+    Text,
+    Json,
+}
+
+fn parse_log_format(arg: &str) -> Result<LogFormat, clap::Error> {
+    match arg.trim().to_lowercase().as_str() {
+        "text" => Ok(LogFormat::Text),
+        "json" => Ok(LogFormat::Json),
+        _ => Err(clap::Error::new(clap::error::ErrorKind::InvalidValue)),
     }
-    paths
 }
 
-fn parse_def_value(v: &str) -> ShaderDefValue {
-    match v.trim().to_lowercase().as_str() {
-        "true" => ShaderDefValue::Bool(true),
-        "false" => ShaderDefValue::Bool(false),
-        other => {
-            if let Some(other) = other.strip_suffix('u') {
-                ShaderDefValue::UInt(other.parse().unwrap())
-            } else {
-                ShaderDefValue::Int(other.parse().unwrap())
-            }
+#[derive(Clone)]
+enum WgslExplicitTypes {
+    All,
+    None,
+}
+
+#[derive(Clone)]
+enum TrailingNewline {
+    Keep,
+    Add,
+    Strip,
+}
+
+impl TrailingNewline {
+    /// Adjust the end of text output: `add` ensures it ends with exactly one newline, `strip` removes any.
+    fn apply(&self, mut text: String) -> String {
+        if let TrailingNewline::Keep = self {
+            return text;
         }
+        text.truncate(text.trim_end_matches(['\n', '\r']).len());
+        if let TrailingNewline::Add = self {
+            text.push('\n');
+        }
+        text
     }
 }
 
-fn gather_defs(args: &[String], add: &[String]) -> HashMap<String, ShaderDefValue> {
-    let mut defs = HashMap::default();
+#[derive(Clone)]
+enum Indent {
+    Tabs,
+    Spaces(usize),
+}
 
-    for def in args.iter().chain(add).flat_map(|def| def.split(';')) {
-        if let Some((name, value)) = def.split_once('=') {
-            defs.insert(name.to_owned(), parse_def_value(value));
-        } else {
-            defs.insert(def.to_owned(), ShaderDefValue::Bool(true));
+impl Indent {
+    /// The width of one indentation level in naga's text backends.
+    const NAGA_WIDTH: usize = 4;
+
+    /// Replace each leading level of naga's four-space indentation. Any remaining spaces, such as
+    /// alignment within a level, are left as they are.
+    fn apply(&self, text: &str) -> String {
+        let unit = match self {
+            Indent::Tabs => "\t".to_owned(),
+            Indent::Spaces(width) => " ".repeat(*width),
+        };
+        let mut indented = String::with_capacity(text.len());
+        for line in text.split_inclusive('\n') {
+            let spaces = line.len() - line.trim_start_matches(' ').len();
+            let levels = spaces / Self::NAGA_WIDTH;
+            indented.push_str(&unit.repeat(levels));
+            indented.push_str(&line[levels * Self::NAGA_WIDTH..]);
         }
+        indented
     }
+}
 
-    defs
+fn parse_indent(arg: &str) -> Result<Indent, clap::Error> {
+    match arg.trim().to_lowercase().as_str() {
+        "tabs" | "tab" => Ok(Indent::Tabs),
+        width => match width.parse() {
+            Ok(width @ 1..=8) => Ok(Indent::Spaces(width)),
+            _ => Err(clap::Error::new(clap::error::ErrorKind::InvalidValue)),
+        },
+    }
 }
 
-fn shader_type(path: &Path) -> Option<ShaderType> {
-    match path.extension() {
-        Some(v) if v.to_string_lossy().to_lowercase() == "wgsl" => Some(ShaderType::Wgsl),
-        Some(v) if v.to_string_lossy().to_lowercase() == "vert" => Some(ShaderType::GlslVertex),
-        Some(v) if v.to_string_lossy().to_lowercase() == "frag" => Some(ShaderType::GlslFragment),
-        _ => None,
+fn parse_trailing_newline(arg: &str) -> Result<TrailingNewline, clap::Error> {
+    match arg.trim().to_lowercase().as_str() {
+        "keep" => Ok(TrailingNewline::Keep),
+        "add" => Ok(TrailingNewline::Add),
+        "strip" => Ok(TrailingNewline::Strip),
+        _ => Err(clap::Error::new(clap::error::ErrorKind::InvalidValue)),
     }
 }
 
-fn input_language(path: &Path) -> Option<ShaderLanguage> {
-    shader_type(path).map(|ty| match ty {
-        ShaderType::Wgsl => ShaderLanguage::Wgsl,
-        ShaderType::GlslVertex | ShaderType::GlslFragment => ShaderLanguage::Glsl,
-    })
+#[derive(Clone)]
+enum ColorChoice {
+    Auto,
+    Always,
+    Never,
 }
 
-fn main() {
-    let cli = Cli::parse();
+impl ColorChoice {
+    /// Whether to color diagnostics. For `auto`, `CLICOLOR_FORCE` takes precedence over `NO_COLOR`,
+    /// and otherwise color is used when stderr is a terminal.
+    fn enabled(&self) -> bool {
+        let var = |name| std::env::var_os(name).filter(|value| !value.is_empty());
+        match self {
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+            ColorChoice::Auto if var("CLICOLOR_FORCE").is_some_and(|value| value != "0") => true,
+            ColorChoice::Auto if var("NO_COLOR").is_some() => false,
+            ColorChoice::Auto => std::io::stderr().is_terminal(),
+        }
+    }
+}
 
-    // gather modules
-    let mut include_paths = gather_paths(&cli.include);
-    let mut includes = HashMap::new();
+fn parse_color(arg: &str) -> Result<ColorChoice, clap::Error> {
+    match arg.trim().to_lowercase().as_str() {
+        "auto" => Ok(ColorChoice::Auto),
+        "always" => Ok(ColorChoice::Always),
+        "never" => Ok(ColorChoice::Never),
+        _ => Err(clap::Error::new(clap::error::ErrorKind::InvalidValue)),
+    }
+}
 
-    while let Some(path) = include_paths.pop() {
-        if path.is_dir() {
-            let Ok(entries) = std::fs::read_dir(&path) else {
-                panic!("failed to read directory {:?}", path);
-            };
-            include_paths.extend(entries.map(|e| e.unwrap().path()));
-        } else {
-            let Some(language) = input_language(&path) else {
-                continue;
-            };
+fn parse_wgsl_explicit_types(arg: &str) -> Result<WgslExplicitTypes, clap::Error> {
+    match arg.trim().to_lowercase().as_str() {
+        "all" => Ok(WgslExplicitTypes::All),
+        "none" => Ok(WgslExplicitTypes::None),
+        _ => Err(clap::Error::new(clap::error::ErrorKind::InvalidValue)),
+    }
+}
 
-            match std::fs::read_to_string(&path) {
-                Err(e) => panic!("failed to read file `{}`: {e}", path.display()),
-                Ok(source) => {
-                    let (name, reqs, _) = naga_oil::compose::get_preprocessor_data(&source);
-                    let name =
-                        name.unwrap_or(format!("\"{}\"", path.to_string_lossy().replace("\\", "/")));
-                    let name = name.strip_prefix("\"./").map(|name| format!("\"{name}")).unwrap_or(name);
-                    eprintln!("found {}", name);
-                    let reqs: HashSet<_> = reqs.into_iter().map(|req| req.import).collect();
-                    if includes.contains_key(&name) {
-                        eprintln!("warning: duplicate definition for `{name}`");
-                    }
-                    includes.insert(name, (reqs, path, language, source));
-                }
-            };
-        }
+fn parse_bounds_check_policy(arg: &str) -> Result<BoundsCheckPolicy, clap::Error> {
+    match arg.trim().to_lowercase().as_str() {
+        "restrict" => Ok(BoundsCheckPolicy::Restrict),
+        "read-zero-skip-write" => Ok(BoundsCheckPolicy::ReadZeroSkipWrite),
+        "unchecked" => Ok(BoundsCheckPolicy::Unchecked),
+        _ => Err(clap::Error::new(clap::error::ErrorKind::InvalidValue)),
     }
+}
 
-    let Ok(source) = std::fs::read_to_string(&cli.shader) else {
-        panic!("failed to read main shader file {}", cli.shader.display());
+fn parse_binding_mapping(
+    arg: &str,
+) -> Result<(naga::ResourceBinding, naga::ResourceBinding), clap::Error> {
+    let invalid = || clap::Error::new(clap::error::ErrorKind::InvalidValue);
+    let binding = |arg: &str| -> Result<naga::ResourceBinding, clap::Error> {
+        let (group, binding) = arg.split_once(':').ok_or_else(invalid)?;
+        Ok(naga::ResourceBinding {
+            group: group.trim().parse().map_err(|_| invalid())?,
+            binding: binding.trim().parse().map_err(|_| invalid())?,
+        })
     };
+    let (source, target) = arg.split_once('=').ok_or_else(invalid)?;
+    Ok((binding(source)?, binding(target)?))
+}
 
-    let (_, reqs, _) = naga_oil::compose::get_preprocessor_data(&source);
-    let mut reqs: HashSet<_> = reqs.into_iter().map(|req| req.import).collect();
+fn parse_spv_version(arg: &str) -> Result<(u8, u8), clap::Error> {
+    let invalid = || clap::Error::new(clap::error::ErrorKind::InvalidValue);
+    let (major, minor) = arg.trim().split_once('.').ok_or_else(invalid)?;
+    let version = (
+        major.parse().map_err(|_| invalid())?,
+        minor.parse().map_err(|_| invalid())?,
+    );
+    match version {
+        (1, 0..=6) => Ok(version),
+        _ => Err(invalid()),
+    }
+}
 
-    let capabilities = Capabilities::all();
+fn parse_spv_capability(arg: &str) -> Result<naga::back::spv::Capability, clap::Error> {
+    arg.trim()
+        .parse()
+        .map_err(|_| clap::Error::new(clap::error::ErrorKind::InvalidValue))
+}
 
-    let mut composer = if cli.no_validation {
-        Composer::non_validating()
-    } else {
-        Composer::default()
+fn parse_timeout(arg: &str) -> Result<f64, clap::Error> {
+    match arg.trim().parse() {
+        Ok(seconds) if seconds > 0.0 && f64::is_finite(seconds) => Ok(seconds),
+        _ => Err(clap::Error::new(clap::error::ErrorKind::InvalidValue)),
     }
-    .with_capabilities(capabilities);
+}
 
-    // add required imports
-    while !reqs.is_empty() {
-        let mut next_reqs: HashSet<String> = HashSet::default();
-        for req in reqs.iter() {
-            if !composer.contains_module(req) {
-                if let Some((subreqs, path, language, source)) = includes.get(req) {
-                    if subreqs
-                        .iter()
-                        .all(|subreq| composer.contains_module(subreq))
-                    {
-                        eprintln!("adding module {req}");
-                        composer
-                            .add_composable_module(ComposableModuleDescriptor {
-                                source,
-                                file_path: &path.to_string_lossy(),
-                                language: *language,
-                                as_name: Some(req.clone()),
-                                ..Default::default()
-                            })
-                            .unwrap();
-                        next_reqs.remove(req);
-                        continue;
-                    }
-                    next_reqs.extend(
-                        subreqs
-                            .iter()
-                            .filter(|r| !composer.contains_module(r))
-                            .cloned(),
-                    );
-                    next_reqs.insert(req.clone());
-                } else {
-                    panic!("required import {} not found in included paths", req);
+fn parse_stage(arg: &str) -> Result<ShaderStage, clap::Error> {
+    match arg.trim().to_lowercase().as_str() {
+        "vertex" | "vert" => Ok(ShaderStage::Vertex),
+        "fragment" | "frag" => Ok(ShaderStage::Fragment),
+        "compute" | "comp" => Ok(ShaderStage::Compute),
+        _ => Err(clap::Error::new(clap::error::ErrorKind::InvalidValue)),
+    }
+}
+
+fn stage_name(stage: ShaderStage) -> &'static str {
+    match stage {
+        ShaderStage::Vertex => "vertex",
+        ShaderStage::Fragment => "fragment",
+        ShaderStage::Compute => "compute",
+    }
+}
+
+fn parse_glsl_version(arg: &str) -> Result<naga::back::glsl::Version, clap::Error> {
+    let arg = arg.trim().to_lowercase();
+    let invalid = |_| clap::Error::new(clap::error::ErrorKind::InvalidValue);
+    match arg.strip_prefix("es") {
+        Some(version) => version
+            .trim_start_matches('-')
+            .parse()
+            .map(naga::back::glsl::Version::new_gles)
+            .map_err(invalid),
+        None => arg
+            .parse()
+            .map(naga::back::glsl::Version::Desktop)
+            .map_err(invalid),
+    }
+}
+
+fn stage_extension(stage: ShaderStage) -> &'static str {
+    match stage {
+        ShaderStage::Vertex => "vert",
+        ShaderStage::Fragment => "frag",
+        ShaderStage::Compute => "comp",
+    }
+}
+
+/// The path of the file written for one entry point when emitting several outputs.
+/// If `output` is a directory the files are named after the input shader and placed inside it,
+/// otherwise they are named after `output` and placed beside it.
+fn entry_output_path(
+    output: &Path,
+    shader: &Path,
+    template: &str,
+    entry_point: &EntryPoint,
+    version: Option<&str>,
+    extension: &str,
+) -> PathBuf {
+    let (dir, stem) = if output.is_dir() {
+        (output, shader.file_stem())
+    } else {
+        (output.parent().unwrap_or(Path::new("")), output.file_stem())
+    };
+    let file_name = template
+        .replace("{stem}", &stem.unwrap_or_default().to_string_lossy())
+        .replace("{entry}", &entry_point.name)
+        .replace("{stage}", stage_name(entry_point.stage))
+        .replace("{version}", version.unwrap_or_default())
+        .replace("{ext}", extension);
+    dir.join(file_name)
+}
+
+fn select_entry_point<'a>(
+    module: &'a naga::Module,
+    name: Option<&str>,
+    stage: Option<ShaderStage>,
+) -> error::Result<&'a EntryPoint> {
+    module
+        .entry_points
+        .iter()
+        .find(|ep| {
+            name.is_none_or(|name| ep.name == name) && stage.is_none_or(|stage| ep.stage == stage)
+        })
+        .ok_or_else(|| {
+            Error::args(match (name, stage) {
+                (Some(name), Some(stage)) => {
+                    format!("no {} entry point named `{name}`", stage_name(stage))
                 }
+                (Some(name), None) => format!("no entry point named `{name}`"),
+                (None, Some(stage)) => format!("no {} entry point", stage_name(stage)),
+                (None, None) => "the shader has no entry points".to_owned(),
+            })
+        })
+}
+
+/// Fail if more than one entry point has `stage` (or any stage, if `None`), listing them, so that
+/// the entry point isn't chosen arbitrarily when none was named.
+fn check_unambiguous_entry_point(
+    module: &naga::Module,
+    stage: Option<ShaderStage>,
+) -> error::Result<()> {
+    let matching: Vec<_> = module
+        .entry_points
+        .iter()
+        .filter(|ep| stage.is_none_or(|stage| ep.stage == stage))
+        .collect();
+    let [first, _, ..] = matching[..] else {
+        return Ok(());
+    };
+    let options = match matching.iter().all(|ep| ep.stage == first.stage) {
+        true => "`--entry-point` or `--entry-index`",
+        false => "`--entry-point`, `--entry-index` or `--stage`",
+    };
+    let entry_points: Vec<_> = matching
+        .iter()
+        .map(|ep| format!("`{}` ({})", ep.name, stage_name(ep.stage)))
+        .collect();
+    Err(Error::args(format!(
+        "the shader has several entry points: {}. Select one with {options}",
+        entry_points.join(", ")
+    )))
+}
+
+fn parse_def_value(v: &str) -> Result<ShaderDefValue, String> {
+    match v.trim().to_lowercase().as_str() {
+        "true" => Ok(ShaderDefValue::Bool(true)),
+        "false" => Ok(ShaderDefValue::Bool(false)),
+        other => {
+            if let Some(unsigned) = other.strip_suffix('u') {
+                unsigned
+                    .parse()
+                    .map(ShaderDefValue::UInt)
+                    .map_err(|_| format!("`{}` is not a valid u32 literal", v.trim()))
+            } else {
+                other.parse().map(ShaderDefValue::Int).map_err(|_| {
+                    format!(
+                        "`{}` is not `true`, `false`, an i32 literal or a u32 literal with a trailing `u`",
+                        v.trim()
+                    )
+                })
             }
         }
+    }
+}
 
-        if next_reqs == reqs {
-            panic!("circular dependency: {:?}", next_reqs)
-        }
-        reqs = next_reqs;
+fn parse_typed_def_value(ty: &str, v: &str) -> Result<ShaderDefValue, String> {
+    let value = v.trim();
+    match ty.trim().to_lowercase().as_str() {
+        "bool" => match value.to_lowercase().as_str() {
+            "true" => Ok(ShaderDefValue::Bool(true)),
+            "false" => Ok(ShaderDefValue::Bool(false)),
+            _ => Err(format!("`{value}` is not a valid bool literal")),
+        },
+        "i32" => value
+            .parse()
+            .map(ShaderDefValue::Int)
+            .map_err(|_| format!("`{value}` is not a valid i32 literal")),
+        "u32" => value
+            .strip_suffix('u')
+            .unwrap_or(value)
+            .parse()
+            .map(ShaderDefValue::UInt)
+            .map_err(|_| format!("`{value}` is not a valid u32 literal")),
+        other => Err(format!(
+            "unknown def type `{other}`, expected `bool`, `i32` or `u32`"
+        )),
     }
+}
 
-    // run composer
-    let composed = composer.make_naga_module(NagaModuleDescriptor {
-        source: &source,
-        file_path: &cli.shader.to_string_lossy(),
-        shader_type: shader_type(&cli.shader)
-            .unwrap_or_else(|| panic!("input shader must have extension `wgsl`, `vert` or `frag`")),
-        shader_defs: gather_defs(&cli.defs, &cli.additional_defs),
-        ..Default::default()
-    });
+/// Merge shader defs. Defs are applied in order, `args` before `add`, so when a name is given
+/// more than once the last value wins.
+fn gather_defs(
+    args: &[String],
+    add: &[String],
+    warn_override: bool,
+) -> error::Result<HashMap<String, ShaderDefValue>> {
+    let mut defs = HashMap::default();
 
-    if let Err(e) = composed {
-        let err_str = e.emit_to_string(&composer);
-        eprintln!("{err_str}");
-        exit(1)
+    for def in args.iter().chain(add).flat_map(|def| def.split(';')) {
+        let (name, value) = match def.split_once('=') {
+            Some((name, value)) => {
+                let (name, value) = match name.split_once(':') {
+                    Some((name, ty)) => (name, parse_typed_def_value(ty, value)),
+                    None => (name, parse_def_value(value)),
+                };
+                let value = value
+                    .map_err(|e| Error::args(format!("invalid value for def `{name}`: {e}")))?;
+                (name, value)
+            }
+            None => match def.split_once(':') {
+                Some((name, ty)) if ty.trim().eq_ignore_ascii_case("bool") => {
+                    (name, ShaderDefValue::Bool(true))
+                }
+                Some((name, ty)) => {
+                    return Err(Error::args(format!(
+                        "def `{name}` of type `{}` needs a value, e.g. `{name}:{}=1`",
+                        ty.trim(),
+                        ty.trim()
+                    )))
+                }
+                None => (def, ShaderDefValue::Bool(true)),
+            },
+        };
+        if let Some(previous) = defs.insert(name.to_owned(), value) {
+            if warn_override {
+                log::warn_at(
+                    None,
+                    format!(
+                        "def `{name}={}` overrides earlier value `{}`",
+                        def_value_string(&defs[name]),
+                        def_value_string(&previous)
+                    ),
+                );
+            }
+        }
     }
 
-    let composed = composed.unwrap();
+    Ok(defs)
+}
 
-    // output
-    let mut target: Box<dyn Write> = cli
-        .output
-        .as_ref()
-        .map(|path| Box::new(std::fs::File::create(path).unwrap()) as Box<dyn Write>)
-        .unwrap_or(Box::new(stdout()));
+/// A command running `command` with the system shell.
+fn shell_command(command: &str) -> std::process::Command {
+    let (shell, flag) = if cfg!(windows) {
+        ("cmd", "/C")
+    } else {
+        ("sh", "-c")
+    };
+    let mut shell = std::process::Command::new(shell);
+    shell.args([flag, command]);
+    shell
+}
 
-    let output_format = cli.format.unwrap_or_else(|| {
-        cli.output
-            .as_ref()
-            .and_then(|path| path.extension().map(|o| o.to_string_lossy().into_owned()))
-            .and_then(|ext| match ext.trim().to_lowercase().as_str() {
-                "wgsl" => Some(OutputFormat::Wgsl),
-                "frag" | "vert" => Some(OutputFormat::Glsl),
-                "json" => Some(OutputFormat::Naga),
-                "spv" | "bin" => Some(OutputFormat::Spirv),
-                _ => None,
-            })
-            .unwrap_or(OutputFormat::Wgsl)
-    });
+/// Run `command` with the system shell, returning the non-empty lines of its output as defs.
+fn run_defs_command(command: &str) -> error::Result<Vec<String>> {
+    let output = shell_command(command)
+        .output()
+        .map_err(|e| Error::io(format!("failed to run defs command `{command}`: {e}")))?;
 
-    let info = naga::valid::Validator::new(naga::valid::ValidationFlags::all(), capabilities)
-        .validate(&composed)
-        .map_err(ComposerErrorInner::HeaderValidationError)
-        .unwrap();
+    if !output.status.success() {
+        return Err(Error::args(format!(
+            "defs command `{command}` failed ({}): {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
 
-    let shader_stage = composed.entry_points.first().unwrap().stage;
-    let entry_point = composed.entry_points.first().unwrap().name.clone();
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(ToOwned::to_owned)
+        .collect())
+}
 
-    match output_format {
-        OutputFormat::Wgsl => {
-            let output = naga::back::wgsl::write_string(
-                &composed,
-                &info,
-                naga::back::wgsl::WriterFlags::EXPLICIT_TYPES,
-            )
-            .unwrap();
-            target.write_all(output.as_bytes()).unwrap();
-        }
-        OutputFormat::Glsl => {
-            let mut string = String::new();
-            let options = naga::back::glsl::Options {
-                version: naga::back::glsl::Version::Desktop(450),
-                writer_flags: naga::back::glsl::WriterFlags::INCLUDE_UNUSED_ITEMS,
-                ..Default::default()
+/// Pipe the source of `path` through the `--transform-cmd` shell command, returning its output.
+pub fn transform_source(command: &str, path: &Path, source: String) -> error::Result<String> {
+    let mut child = shell_command(command)
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .map_err(|e| Error::io(format!("failed to run transform command `{command}`: {e}")))?;
+    // write from another thread so that a command producing output as it reads can't deadlock
+    let mut stdin = child.stdin.take().unwrap();
+    let writer = std::thread::spawn(move || stdin.write_all(source.as_bytes()));
+    let output = child
+        .wait_with_output()
+        .map_err(|e| Error::io(format!("failed to run transform command `{command}`: {e}")))?;
+    // the command may legitimately exit without reading all of its input
+    let _ = writer.join();
+
+    if !output.status.success() {
+        return Err(Error::args(format!(
+            "transform command `{command}` failed for `{}` ({}): {}",
+            path.display(),
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    String::from_utf8(output.stdout).map_err(|_| {
+        Error::args(format!(
+            "transform command `{command}` produced invalid UTF-8 for `{}`",
+            path.display()
+        ))
+    })
+}
+
+fn def_type_name(value: &ShaderDefValue) -> &'static str {
+    match value {
+        ShaderDefValue::Bool(_) => "bool",
+        ShaderDefValue::Int(_) => "int",
+        ShaderDefValue::UInt(_) => "uint",
+    }
+}
+
+/// Check supplied defs against the types implied by `#define`s in the shader and its modules.
+/// Numeric literals in a `#define` are read as unsigned where possible, so only bool/numeric
+/// mismatches are reported.
+fn check_def_types<'a>(
+    defs: &HashMap<String, ShaderDefValue>,
+    declarations: impl Iterator<Item = (&'a Path, &'a HashMap<String, ShaderDefValue>)>,
+) -> error::Result<()> {
+    for (path, defines) in declarations {
+        for (name, declared) in defines {
+            let Some(value) = defs.get(name) else {
+                continue;
             };
-            let pipeline_options = naga::back::glsl::PipelineOptions {
-                shader_stage,
-                entry_point,
-                multiview: None,
+            if matches!(declared, ShaderDefValue::Bool(_))
+                != matches!(value, ShaderDefValue::Bool(_))
+            {
+                return Err(Error::args(format!(
+                    "def `{name}` expected {} (as declared in `{}`) but got {}",
+                    def_type_name(declared),
+                    path.display(),
+                    def_type_name(value)
+                )));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn parse_prefixed_include(arg: &str) -> Result<(String, PathBuf), clap::Error> {
+    match arg.split_once(':') {
+        Some((prefix, path)) if !prefix.trim().is_empty() && !path.is_empty() => {
+            Ok((prefix.trim().to_owned(), PathBuf::from(path)))
+        }
+        _ => Err(clap::Error::new(clap::error::ErrorKind::InvalidValue)),
+    }
+}
+
+fn parse_language(arg: &str) -> Result<ShaderLanguage, clap::Error> {
+    match arg.trim().to_lowercase().as_str() {
+        "wgsl" => Ok(ShaderLanguage::Wgsl),
+        "glsl" => Ok(ShaderLanguage::Glsl),
+        _ => Err(clap::Error::new(clap::error::ErrorKind::InvalidValue)),
+    }
+}
+
+fn parse_module_lang(arg: &str) -> Result<(String, ShaderLanguage), clap::Error> {
+    let invalid = || clap::Error::new(clap::error::ErrorKind::InvalidValue);
+    let (extension, language) = arg.split_once('=').ok_or_else(invalid)?;
+    let language = parse_language(language)?;
+    let extension = extension.trim().trim_start_matches('.').to_lowercase();
+    Ok((extension, language))
+}
+
+/// Expand `@file` arguments in place with the newline-separated arguments contained in `file`.
+fn expand_response_files(args: impl Iterator<Item = String>) -> error::Result<Vec<String>> {
+    let mut expanded = Vec::default();
+    for arg in args {
+        let Some(path) = arg.strip_prefix('@') else {
+            expanded.push(arg);
+            continue;
+        };
+
+        match std::fs::read_to_string(path) {
+            Err(e) => {
+                return Err(Error::io(format!(
+                    "failed to read response file `{path}`: {e}"
+                )))
+            }
+            Ok(contents) => expanded.extend(
+                contents
+                    .lines()
+                    .map(str::trim)
+                    .filter(|line| !line.is_empty())
+                    .map(ToOwned::to_owned),
+            ),
+        }
+    }
+    Ok(expanded)
+}
+
+/// Parse the command line. An entry point from `NAGA_OIL_ENTRY_POINT` is only a default, so the
+/// options that select the entry point another way are checked against `--entry-point` here
+/// rather than by clap, which would also reject the variable: an entry point given on the command
+/// line conflicts with them, while one from the environment is dropped.
+fn parse_args(args: &[String]) -> Result<Cli, clap::Error> {
+    let mut command = Cli::command();
+    let matches = command.try_get_matches_from_mut(args)?;
+    let mut cli = Cli::from_arg_matches(&matches)?;
+    let selector = ["entry_index", "compile_tree", "strip_entry_points", "stage"]
+        .into_iter()
+        .find(|id| matches.value_source(id) == Some(ValueSource::CommandLine));
+    match (matches.value_source("entry_point"), selector) {
+        (Some(ValueSource::CommandLine), Some(id)) if id != "stage" => {
+            let arg = |id: &str| {
+                command
+                    .get_arguments()
+                    .find(|arg| arg.get_id() == id)
+                    .map(ToString::to_string)
+                    .unwrap_or_default()
             };
-            let mut writer = naga::back::glsl::Writer::new(
-                &mut string,
-                &composed,
-                &info,
-                &options,
-                &pipeline_options,
-                naga::proc::BoundsCheckPolicies::default(),
+            return Err(command.error(
+                clap::error::ErrorKind::ArgumentConflict,
+                format!(
+                    "the argument '{}' cannot be used with '{}'",
+                    arg("entry_point"),
+                    arg(id)
+                ),
+            ));
+        }
+        // an empty variable sets no default
+        (Some(ValueSource::EnvVariable), selector)
+            if selector.is_some() || cli.entry_point.as_deref() == Some("") =>
+        {
+            cli.entry_point = None
+        }
+        _ => (),
+    }
+    Ok(cli)
+}
+
+/// Find the file requested with `--dotenv`, which has to be found before the full argument
+/// parse as the variables it sets are read by that parse. The arguments are parsed leniently, so that
+/// values of other flags and arguments after `--` aren't mistaken for it.
+fn dotenv_arg(args: &[String]) -> Option<(PathBuf, bool)> {
+    let matches = Cli::command()
+        .ignore_errors(true)
+        .try_get_matches_from(args)
+        .ok()?;
+    if matches.value_source("dotenv") != Some(ValueSource::CommandLine) {
+        return None;
+    }
+    Some(match matches.get_one::<PathBuf>("dotenv") {
+        Some(path) => (path.clone(), true),
+        None => (PathBuf::from(".env"), false),
+    })
+}
+
+/// Set environment variables from `KEY=VALUE` lines in a `.env` file. Variables that are already
+/// set are left alone, so the file has the lowest precedence.
+fn load_dotenv(path: &Path, required: bool) -> error::Result<()> {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound && !required => return Ok(()),
+        Err(e) => {
+            return Err(Error::io(format!(
+                "failed to read env file `{}`: {e}",
+                path.display()
+            )))
+        }
+    };
+    for (index, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let Some((key, value)) = line.split_once('=') else {
+            return Err(Error::args(format!(
+                "{}:{}: expected `KEY=VALUE`",
+                path.display(),
+                index + 1
+            )));
+        };
+        let value = value.trim();
+        let value = [('"', '"'), ('\'', '\'')]
+            .iter()
+            .find_map(|(open, close)| value.strip_prefix(*open)?.strip_suffix(*close))
+            .unwrap_or(value);
+        let key = key.trim();
+        if std::env::var_os(key).is_none() {
+            std::env::set_var(key, value);
+        }
+    }
+    Ok(())
+}
+
+/// A hash of the inputs to composition: the main shader, the defs, and the name and source of every
+/// module the shader imports directly or indirectly. Everything is sorted first, so the hash doesn't
+/// depend on the order modules were scanned in.
+fn graph_hash(
+    shader: &Path,
+    source: &str,
+    reqs: &[String],
+    includes: &HashMap<String, IncludedModule>,
+    shader_defs: &HashMap<String, ShaderDefValue>,
+) -> u64 {
+    let mut imported = BTreeSet::new();
+    let mut pending = reqs.to_vec();
+    while let Some(name) = pending.pop() {
+        let Some(module) = includes.get(&name) else {
+            continue;
+        };
+        if imported.insert(name) {
+            pending.extend(module.reqs.iter().cloned());
+        }
+    }
+
+    // entries are separated by a byte that can't appear in the text. Only the shader's file name is
+    // included, whose extension picks the shader type, so the hash doesn't depend on the working directory
+    let mut inputs = format!(
+        "{}\0{:016x}\0",
+        shader.file_name().unwrap_or_default().to_string_lossy(),
+        content_hash(source.as_bytes())
+    );
+    let defs: BTreeMap<_, _> = shader_defs.iter().collect();
+    for (name, value) in defs {
+        inputs.push_str(&format!("def {name}={}\0", def_value_string(value)));
+    }
+    for name in imported {
+        let hash = content_hash(includes[&name].source.as_bytes());
+        inputs.push_str(&format!("module {name}={hash:016x}\0"));
+    }
+    content_hash(inputs.as_bytes())
+}
+
+/// Every import reachable from the main shader that isn't among the scanned modules, with the
+/// modules that import it.
+fn missing_imports(
+    shader: &Path,
+    reqs: &[String],
+    includes: &HashMap<String, IncludedModule>,
+) -> BTreeMap<String, BTreeSet<String>> {
+    let mut missing: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+    let mut visited = BTreeSet::new();
+    let mut pending: Vec<_> = reqs
+        .iter()
+        .map(|req| (shader.display().to_string(), req.clone()))
+        .collect();
+    while let Some((importer, name)) = pending.pop() {
+        match includes.get(&name) {
+            None => {
+                missing.entry(name).or_default().insert(importer);
+            }
+            Some(module) => {
+                if visited.insert(name.clone()) {
+                    pending.extend(module.reqs.iter().map(|req| (name.clone(), req.clone())));
+                }
+            }
+        }
+    }
+    missing
+}
+
+/// Add the module `name` to the composer after recursively adding its imports.
+/// `stack` holds the chain of modules currently being added, for detecting circular dependencies.
+/// With `explain`, each decision is logged along with the chain of importing modules.
+fn add_module_with_imports(
+    composer: &mut Composer,
+    includes: &HashMap<String, IncludedModule>,
+    name: &str,
+    stack: &mut Vec<String>,
+    added: &mut Vec<String>,
+    explain: bool,
+) -> error::Result<()> {
+    let trace = |stack: &[String], message: String| {
+        if explain {
+            let chain = match stack.is_empty() {
+                true => "main shader".to_owned(),
+                false => stack.join(" -> "),
+            };
+            log::info(format!("resolve: {message} (imported via {chain})"));
+        }
+    };
+
+    if composer.contains_module(name) {
+        trace(stack, format!("`{name}` skipped, already added"));
+        return Ok(());
+    }
+
+    if stack.iter().any(|item| item == name) {
+        return Err(Error::compose(format!(
+            "circular dependency: {} -> {name}",
+            stack.join(" -> ")
+        ))
+        .with_hint("modules can't import each other in a loop; move the items they share into a new module that both import"));
+    }
+
+    let Some(module) = includes.get(name) else {
+        trace(stack, format!("`{name}` not found in the scanned modules"));
+        return Err(Error::unresolved_import(format!(
+            "required import {name} not found in included paths"
+        ))
+        .with_hint(error::NOT_FOUND_HINT));
+    };
+
+    if module.reqs.iter().any(|req| req == name) {
+        return Err(Error::compose(format!("module {name} imports itself"))
+            .with_hint("remove the `#import` of the module's own `#define_import_path`"));
+    }
+
+    if module.reqs.is_empty() {
+        trace(
+            stack,
+            format!(
+                "`{name}` found at `{}`, with no imports",
+                module.path.display()
+            ),
+        );
+    } else {
+        trace(
+            stack,
+            format!(
+                "`{name}` found at `{}`, deferred until its imports are added: {}",
+                module.path.display(),
+                module.reqs.join(", ")
+            ),
+        );
+    }
+
+    stack.push(name.to_owned());
+    for req in module.reqs.iter() {
+        add_module_with_imports(composer, includes, req, stack, added, explain)?;
+    }
+    stack.pop();
+
+    let result = composer.add_composable_module(ComposableModuleDescriptor {
+        source: &module.source,
+        file_path: &module.path.to_string_lossy(),
+        language: module.language,
+        as_name: Some(name.to_owned()),
+        ..Default::default()
+    });
+    if let Err(e) = result {
+        return Err(Error::composer(&e, composer));
+    }
+    added.push(name.to_owned());
+    trace(stack, format!("`{name}` added"));
+    Ok(())
+}
+
+fn main() {
+    let args = expand_response_files(std::env::args()).unwrap_or_else(|e| e.exit());
+    if let Some((path, required)) = dotenv_arg(&args) {
+        load_dotenv(&path, required).unwrap_or_else(|e| e.exit());
+    }
+    let cli = match parse_args(&args) {
+        Ok(cli) => cli,
+        // help and version output
+        Err(e) if !e.use_stderr() => e.exit(),
+        Err(e) => {
+            let _ = e.print();
+            exit(ErrorKind::Args.exit_code());
+        }
+    };
+    log::set_color(cli.color.enabled());
+    log::set_json(matches!(cli.log_format, LogFormat::Json));
+    error::set_explain(cli.explain_error);
+    if cli.quiet_success {
+        log::buffer();
+    }
+
+    let result = run(&cli).and_then(|_| {
+        if cli.deny_warnings && log::warning_count() > 0 {
+            return Err(Error::compose(format!(
+                "{} warning(s) emitted with --deny-warnings",
+                log::warning_count()
+            )));
+        }
+        Ok(())
+    });
+
+    // errors already recorded individually (by `--compile-tree`) are summarized by the final error
+    if let (Err(e), 0) = (&result, log::error_count()) {
+        log::record(Diagnostic {
+            severity: Severity::Error,
+            message: e.message.clone(),
+            location: e.location.as_deref().cloned(),
+        });
+    }
+
+    if let Some(path) = cli.diagnostics_out.as_ref() {
+        if let Err(e) = write_diagnostics(path) {
+            e.exit();
+        }
+    }
+
+    if let Err(e) = result {
+        e.print();
+        log::summary();
+        exit(e.kind.exit_code());
+    }
+    log::summary();
+}
+
+/// Write the diagnostics recorded so far as JSON, for `--diagnostics-out`.
+fn write_diagnostics(path: &Path) -> error::Result<()> {
+    let diagnostics: Vec<_> = log::diagnostics().iter().map(Diagnostic::to_json).collect();
+    let json = serde_json::to_vec_pretty(&diagnostics).unwrap();
+    write_output_file(path, &json, true)
+}
+
+fn run(cli: &Cli) -> error::Result<()> {
+    if let Some(path) = &cli.inspect {
+        return inspect(path);
+    }
+
+    let unpacked;
+    let mut packed = Vec::new();
+    let cli = match &cli.unpack {
+        Some(path) => {
+            // `unpack` always marks exactly one entry
+            let (mut entry, others): (Vec<_>, _) =
+                scan::unpack(path)?.into_iter().partition(|file| file.entry);
+            let entry = entry.pop().unwrap();
+            packed = others;
+            unpacked = Cli {
+                shader: entry.path,
+                unpacked_source: Some(entry.source),
+                ..cli.clone()
+            };
+            &unpacked
+        }
+        None => cli,
+    };
+
+    let from_naga;
+    let cli = match &cli.from_naga {
+        // the module's file stands in for the shader when naming outputs
+        Some(path) => {
+            from_naga = Cli {
+                shader: path.clone(),
+                ..cli.clone()
+            };
+            &from_naga
+        }
+        None => cli,
+    };
+
+    let includes = match cli.from_naga {
+        Some(_) => HashMap::new(),
+        None => scan::scan_includes(cli, packed)?,
+    };
+
+    match &cli.compile_tree {
+        Some(dir) => compile_tree(cli, dir, &includes),
+        None => compile(cli, includes),
+    }
+}
+
+/// Print the preprocessor's view of a single file as JSON.
+fn inspect(path: &Path) -> error::Result<()> {
+    let source = std::fs::read_to_string(path)
+        .map_err(|e| Error::io(format!("failed to read file `{}`: {e}", path.display())))?;
+    let (name, imports, defines) = naga_oil::compose::get_preprocessor_data(&source);
+    let referenced_defs: BTreeSet<_> = naga_oil::compose::preprocess::Preprocessor::default()
+        .get_preprocessor_metadata(&source, true)
+        .map(|metadata| metadata.effective_defs.into_iter().collect())
+        .unwrap_or_default();
+    let json = serde_json::json!({
+        "file": path.display().to_string(),
+        "name": name,
+        "imports": imports
+            .iter()
+            .map(|import| serde_json::json!({ "import": import.import, "items": import.items }))
+            .collect::<Vec<_>>(),
+        "defines": defines
+            .iter()
+            .map(|(name, value)| (name.clone(), def_value_string(value)))
+            .collect::<BTreeMap<_, _>>(),
+        "referenced_defs": referenced_defs,
+    });
+    println!("{}", serde_json::to_string_pretty(&json).unwrap());
+    Ok(())
+}
+
+/// Compile each shader with an entry point under `dir`, mirroring the directory structure under `--output`.
+/// Failures are reported as they happen, and all shaders are attempted.
+fn compile_tree(
+    cli: &Cli,
+    dir: &Path,
+    includes: &HashMap<String, IncludedModule>,
+) -> error::Result<()> {
+    let Some(output_dir) = cli.output_path() else {
+        return Err(Error::args("`--compile-tree` requires an output directory"));
+    };
+    let extension = match cli.format {
+        Some(OutputFormat::Glsl) => "glsl",
+        Some(OutputFormat::Naga) => "json",
+        Some(OutputFormat::Spirv) => "spv",
+        Some(OutputFormat::Wgsl) | None => "wgsl",
+    };
+
+    // library modules are only recognised once composed, as having no entry points
+    let mut shaders = Vec::new();
+    let mut pending = vec![(0, dir.to_owned())];
+    while let Some((depth, path)) = pending.pop() {
+        if path.is_dir() {
+            if cli.max_depth.is_some_and(|max_depth| depth > max_depth) {
+                continue;
+            }
+            let entries = std::fs::read_dir(&path)
+                .and_then(|entries| {
+                    entries
+                        .map(|entry| entry.map(|entry| entry.path()))
+                        .collect::<Result<Vec<_>, _>>()
+                })
+                .map_err(|e| {
+                    Error::io(format!(
+                        "failed to read directory `{}`: {e}",
+                        path.display()
+                    ))
+                })?;
+            pending.extend(entries.into_iter().map(|entry| (depth + 1, entry)));
+        } else if shader_type(&path).is_some() {
+            shaders.push(path);
+        }
+    }
+    shaders.sort();
+
+    // the input extension is kept unless it is the output's, so that e.g. `a.vert` and `a.frag` get separate outputs
+    let mut outputs: HashMap<PathBuf, &Path> = HashMap::new();
+    let mut targets = Vec::new();
+    for shader in shaders.iter() {
+        let relative = shader.strip_prefix(dir).unwrap_or(shader);
+        let output = match relative.extension() {
+            Some(ext) if ext == extension => output_dir.join(relative),
+            _ => {
+                let mut file_name = relative.as_os_str().to_owned();
+                file_name.push(format!(".{extension}"));
+                output_dir.join(file_name)
+            }
+        };
+        if let Some(other) = outputs.insert(output.clone(), shader) {
+            return Err(Error::args(format!(
+                "`{}` and `{}` would both be written to `{}`",
+                other.display(),
+                shader.display(),
+                output.display()
+            )));
+        }
+        targets.push((shader, output));
+    }
+
+    let mut failed = 0;
+    for (shader, output) in targets {
+        log::event(
+            "compiling",
+            serde_json::json!({ "file": shader.display().to_string() }),
+            format!("compiling `{}`", shader.display()),
+        );
+        let shader_cli = Cli {
+            shader: shader.clone(),
+            output: Some(output.clone()),
+            format: Some(cli.format.clone().unwrap_or(OutputFormat::Wgsl)),
+            all_entry_points: true,
+            ..cli.clone()
+        };
+        if let Err(e) = compile(&shader_cli, includes.clone()) {
+            e.print();
+            log::record(Diagnostic {
+                severity: Severity::Error,
+                message: e.message.clone(),
+                location: e.location.as_deref().cloned(),
+            });
+            failed += 1;
+        }
+    }
+
+    match failed {
+        0 => Ok(()),
+        failed => Err(Error::compose(format!(
+            "{failed} shader(s) under `{}` failed to compile",
+            dir.display()
+        ))),
+    }
+}
+
+/// Write the module graph as JSON: a node for the main shader and each scanned module, marking
+/// those the shader uses, and an edge for each import.
+fn write_graph_json(
+    path: &Path,
+    shader: &Path,
+    shader_language: ShaderLanguage,
+    reqs: &[String],
+    includes: &HashMap<String, IncludedModule>,
+    added: &[String],
+) -> error::Result<()> {
+    let language_name = |language| match language {
+        ShaderLanguage::Wgsl => "wgsl",
+        ShaderLanguage::Glsl => "glsl",
+    };
+    let shader_name = shader.display().to_string();
+    let modules: BTreeMap<_, _> = includes.iter().collect();
+
+    let mut nodes = vec![serde_json::json!({
+        "name": shader_name,
+        "path": shader_name,
+        "language": language_name(shader_language),
+        "main": true,
+        "used": true,
+    })];
+    let mut edges: Vec<_> = reqs
+        .iter()
+        .map(|req| serde_json::json!({ "from": shader_name, "to": req }))
+        .collect();
+    for (name, module) in modules {
+        nodes.push(serde_json::json!({
+            "name": name,
+            "path": module.path.display().to_string(),
+            "language": language_name(module.language),
+            "main": false,
+            "used": added.contains(name),
+        }));
+        edges.extend(
+            module
+                .reqs
+                .iter()
+                .map(|req| serde_json::json!({ "from": name, "to": req })),
+        );
+    }
+
+    let graph = serde_json::json!({ "nodes": nodes, "edges": edges });
+    let contents = serde_json::to_string_pretty(&graph)
+        .map_err(|e| Error::io(format!("failed to serialize module graph: {e}")))?;
+    write_output_file(path, format!("{contents}\n").as_bytes(), true)
+}
+
+/// Check that each module resolves to the file recorded in `lockfile`, recording any module that isn't in it yet.
+fn check_lockfile(
+    lockfile: &Path,
+    added: &[String],
+    includes: &HashMap<String, IncludedModule>,
+    atomic: bool,
+) -> error::Result<()> {
+    let mut locked: BTreeMap<String, String> = match std::fs::read_to_string(lockfile) {
+        Ok(contents) => serde_json::from_str::<serde_json::Value>(&contents)
+            .ok()
+            .and_then(|lock| serde_json::from_value(lock.get("modules")?.clone()).ok())
+            .ok_or_else(|| Error::args(format!("invalid lockfile `{}`", lockfile.display())))?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+        Err(e) => {
+            return Err(Error::io(format!(
+                "failed to read lockfile `{}`: {e}",
+                lockfile.display()
+            )))
+        }
+    };
+
+    let directory = lockfile
+        .parent()
+        .filter(|directory| !directory.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    let directory = normalize_path(directory);
+    let mut changed = false;
+    for name in added {
+        let path = relative_path(&normalize_path(&includes[name].path), &directory);
+        match locked.get(name) {
+            Some(locked_path) if *locked_path != path => {
+                return Err(Error::compose(format!(
+                    "module `{name}` resolved to `{path}`, but `{}` locks it to `{locked_path}`",
+                    lockfile.display()
+                )))
+            }
+            Some(_) => (),
+            None => {
+                locked.insert(name.clone(), path);
+                changed = true;
+            }
+        }
+    }
+
+    if changed {
+        let contents = serde_json::to_string_pretty(&serde_json::json!({ "modules": locked }))
+            .map_err(|e| Error::io(format!("failed to serialize lockfile: {e}")))?;
+        write_output_file(lockfile, format!("{contents}\n").as_bytes(), atomic)?;
+    }
+    Ok(())
+}
+
+/// `path` relative to `base`, both normalized, with `/` separators.
+fn relative_path(path: &Path, base: &Path) -> String {
+    let path: Vec<_> = path.components().collect();
+    let base: Vec<_> = base.components().collect();
+    let common = path.iter().zip(&base).take_while(|(a, b)| a == b).count();
+    std::iter::repeat_n("..".into(), base.len() - common)
+        .chain(
+            path[common..]
+                .iter()
+                .map(|c| c.as_os_str().to_string_lossy()),
+        )
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn compile(cli: &Cli, mut includes: HashMap<String, IncludedModule>) -> error::Result<()> {
+    if let Some(path) = &cli.from_naga {
+        let watchdog = cli
+            .timeout
+            .map(|seconds| Watchdog::start(seconds, cli.diagnostics_out.clone()));
+        let composition = Composition {
+            source: "",
+            shader_type: None,
+            modules: Vec::new(),
+            shader_defs: &HashMap::new(),
+            original_source: None,
+        };
+        return write_module(cli, read_naga_module(path)?, &composition, watchdog);
+    }
+
+    let mut source = match &cli.unpacked_source {
+        Some(source) => source.clone(),
+        None => std::fs::read_to_string(&cli.shader).map_err(|e| {
+            Error::io(format!(
+                "failed to read main shader file `{}`: {e}",
+                cli.shader.display()
+            ))
+        })?,
+    };
+    if let Some(command) = &cli.transform_cmd {
+        source = transform_source(command, &cli.shader, source)?;
+    }
+    // kept before imports are rewritten, for `--embed-source`
+    let original_source = cli.embed_source.then(|| source.clone());
+    if source.trim().is_empty() {
+        return Err(Error::compose(format!(
+            "shader file `{}` is empty",
+            cli.shader.display()
+        )));
+    }
+
+    if cli.glsl_includes {
+        let sources_by_path: HashMap<_, _> = includes
+            .values()
+            .map(|module| (normalize_path(&module.path), module.source.clone()))
+            .collect();
+        for module in includes
+            .values_mut()
+            .filter(|module| module.language == ShaderLanguage::Glsl)
+        {
+            module.source = inline_glsl_includes(&module.source, &module.path, &sources_by_path)?;
+            let (_, reqs, defines) = naga_oil::compose::get_preprocessor_data(&module.source);
+            module.reqs = import_names(reqs);
+            module.defines = defines;
+        }
+        if scan::input_language(&cli.shader) != Some(ShaderLanguage::Wgsl) {
+            source = inline_glsl_includes(&source, &cli.shader, &sources_by_path)?;
+        }
+    }
+
+    let (_, reqs, shader_defines) = naga_oil::compose::get_preprocessor_data(&source);
+    let mut reqs = import_names(reqs);
+
+    let names_by_path: HashMap<_, _> = includes
+        .iter()
+        .map(|(name, module)| (normalize_path(&module.path), name.clone()))
+        .collect();
+    let importer = |path| cli.relative_imports.then_some(path);
+    for module in includes.values_mut() {
+        resolve_path_imports(
+            &mut module.source,
+            &mut module.reqs,
+            importer(module.path.as_path()),
+            &names_by_path,
+        );
+    }
+    resolve_path_imports(
+        &mut source,
+        &mut reqs,
+        importer(cli.shader.as_path()),
+        &names_by_path,
+    );
+
+    if cli.list_defs {
+        print_def_references(&cli.shader, &source, &includes);
+        return Ok(());
+    }
+
+    let mut defs = cli.defs.clone();
+    if let Some(command) = &cli.defs_cmd {
+        defs.extend(run_defs_command(command)?);
+    }
+    let shader_defs = gather_defs(&defs, &cli.additional_defs, cli.warn_def_override)?;
+    let missing_defs: Vec<_> = cli
+        .require_def
+        .iter()
+        .filter(|name| !shader_defs.contains_key(name.as_str()))
+        .map(|name| format!("`{name}`"))
+        .collect();
+    if !missing_defs.is_empty() {
+        return Err(Error::args(format!(
+            "required def(s) not given: {}",
+            missing_defs.join(", ")
+        )));
+    }
+
+    if cli.graph_hash {
+        println!(
+            "{:016x}",
+            graph_hash(&cli.shader, &source, &reqs, &includes, &shader_defs)
+        );
+        return Ok(());
+    }
+
+    let capabilities = Capabilities::all();
+
+    let mut composer = if cli.no_validation {
+        Composer::non_validating()
+    } else {
+        Composer::default()
+    }
+    .with_capabilities(capabilities);
+
+    let main_shader_type = match shader_type(&cli.shader) {
+        Some(ty) => ty,
+        None => front_matter_shader_type(&source)?.ok_or_else(|| {
+            Error::args("input shader must have extension `wgsl`, `vert` or `frag`, or declare its type with a `//! naga_oil: type=...` comment")
+        })?,
+    };
+
+    if cli.collect_missing {
+        let missing = missing_imports(&cli.shader, &reqs, &includes);
+        if !missing.is_empty() {
+            let missing: Vec<_> = missing
+                .iter()
+                .map(|(name, importers)| {
+                    let importers: Vec<_> =
+                        importers.iter().map(|name| format!("`{name}`")).collect();
+                    format!("`{name}`, imported by {}", importers.join(", "))
+                })
+                .collect();
+            return Err(Error::unresolved_import(format!(
+                "{} required import(s) not found in included paths:\n  {}",
+                missing.len(),
+                missing.join("\n  ")
+            ))
+            .with_hint(error::NOT_FOUND_HINT));
+        }
+    }
+
+    // add required imports, dependencies first
+    let mut added = Vec::default();
+    let adding = reqs.iter().try_for_each(|req| {
+        add_module_with_imports(
+            &mut composer,
+            &includes,
+            req,
+            &mut Vec::default(),
+            &mut added,
+            cli.explain_resolution,
+        )
+    });
+    // log the modules added so far before reporting a failure, to show how far the buildup got
+    match cli.log_order {
+        LogOrder::Topo => (),
+        LogOrder::Alpha => added.sort(),
+    }
+    for name in added.iter() {
+        log::event(
+            "module_added",
+            serde_json::json!({ "module": name }),
+            format!("adding module {name}"),
+        );
+    }
+    adding?;
+
+    if let Some(path) = &cli.graph_json {
+        let language = match main_shader_type {
+            ShaderType::Wgsl => ShaderLanguage::Wgsl,
+            ShaderType::GlslVertex | ShaderType::GlslFragment => ShaderLanguage::Glsl,
+        };
+        write_graph_json(path, &cli.shader, language, &reqs, &includes, &added)?;
+    }
+
+    if let Some(lockfile) = &cli.lockfile {
+        check_lockfile(lockfile, &added, &includes, cli.atomic_output)?;
+    }
+
+    if cli.warn_unused_modules {
+        let shader_path = normalize_path(&cli.shader);
+        let mut unused: Vec<_> = includes
+            .iter()
+            .filter(|(name, module)| {
+                !added.contains(name) && normalize_path(&module.path) != shader_path
+            })
+            .collect();
+        unused.sort_by_key(|(name, _)| name.as_str());
+        for (name, module) in unused {
+            log::warn_at(
+                Some(Location::file(module.path.display())),
+                format!("module `{name}` is not used by `{}`", cli.shader.display()),
+            );
+        }
+    }
+
+    if cli.isolate {
+        // only collisions between modules that are actually used matter in isolate mode
+        let mut collisions = added
+            .iter()
+            .filter_map(|name| {
+                let module = &includes[name];
+                (!module.shadowed.is_empty()).then(|| {
+                    let paths: Vec<_> = module
+                        .shadowed
+                        .iter()
+                        .chain(std::iter::once(&module.path))
+                        .map(|path| format!("`{}`", path.display()))
+                        .collect();
+                    format!("`{name}` is defined by {}", paths.join(", "))
+                })
+            })
+            .collect::<Vec<_>>();
+        if !collisions.is_empty() {
+            collisions.sort();
+            return Err(Error::compose(format!(
+                "conflicting definitions for required modules:\n  {}",
+                collisions.join("\n  ")
+            )));
+        }
+    }
+
+    // run composer
+    check_def_types(
+        &shader_defs,
+        std::iter::once((cli.shader.as_path(), &shader_defines)).chain(
+            added
+                .iter()
+                .map(|name| (includes[name].path.as_path(), &includes[name].defines)),
+        ),
+    )?;
+    let watchdog = cli
+        .timeout
+        .map(|seconds| Watchdog::start(seconds, cli.diagnostics_out.clone()));
+    let composed = composer
+        .make_naga_module(NagaModuleDescriptor {
+            source: &source,
+            file_path: &cli.shader.to_string_lossy(),
+            shader_type: main_shader_type,
+            shader_defs: shader_defs.clone(),
+            ..Default::default()
+        })
+        .map_err(|e| Error::composer(&e, &composer))?;
+
+    let modules: Vec<_> = added
+        .iter()
+        .map(|name| (name.as_str(), includes[name].path.as_path()))
+        .collect();
+    write_module(
+        cli,
+        composed,
+        &Composition {
+            source: &source,
+            shader_type: Some(main_shader_type),
+            modules,
+            shader_defs: &shader_defs,
+            original_source: original_source.as_deref(),
+        },
+        watchdog,
+    )
+}
+
+/// The main shader, defs and modules that produced a module, for `--embed-source` and `--sidecar`.
+fn composition_json(cli: &Cli, composition: &Composition) -> serde_json::Value {
+    serde_json::json!({
+        "shader": cli.shader.display().to_string(),
+        "defs": composition.shader_defs
+            .iter()
+            .map(|(name, value)| (name.clone(), def_value_string(value)))
+            .collect::<BTreeMap<_, _>>(),
+        "modules": composition
+            .modules
+            .iter()
+            .map(|(name, path)| serde_json::json!({ "name": name, "path": path.display().to_string() }))
+            .collect::<Vec<_>>(),
+    })
+}
+
+/// Write `<output>.defs.json` next to an output file, for `--sidecar`.
+fn write_sidecar(cli: &Cli, output: &Path, composition: &Composition) -> error::Result<()> {
+    let file_name = output.file_name().unwrap_or_default().to_string_lossy();
+    let path = output.with_file_name(format!("{file_name}.defs.json"));
+    let contents = serde_json::to_string_pretty(&composition_json(cli, composition)).unwrap();
+    write_output_file(&path, format!("{contents}\n").as_bytes(), cli.atomic_output)
+}
+
+/// Read a module written as NAGA output, unwrapping it from the `--embed-source` envelope if necessary.
+fn read_naga_module(path: &Path) -> error::Result<naga::Module> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| Error::io(format!("failed to read module `{}`: {e}", path.display())))?;
+    let invalid = |e: serde_json::Error| {
+        Error::args(format!("invalid naga module `{}`: {e}", path.display()))
+    };
+    let mut json: serde_json::Value = serde_json::from_str(&contents).map_err(invalid)?;
+    if let Some(module) = json.get_mut("module") {
+        json = module.take();
+    }
+    serde_json::from_value(json).map_err(invalid)
+}
+
+/// What went into a composed module, for output that refers back to its sources.
+struct Composition<'a> {
+    source: &'a str,
+    shader_type: Option<ShaderType>,
+    /// The names and paths of the imported modules
+    modules: Vec<(&'a str, &'a Path)>,
+    shader_defs: &'a HashMap<String, ShaderDefValue>,
+    original_source: Option<&'a str>,
+}
+
+/// Validate a module and write it in the requested output formats. `watchdog` is disarmed once validation is done.
+fn write_module(
+    cli: &Cli,
+    composed: naga::Module,
+    composition: &Composition,
+    mut watchdog: Option<Watchdog>,
+) -> error::Result<()> {
+    if cli.compile_tree.is_some() && composed.entry_points.is_empty() {
+        log::event(
+            "library_skipped",
+            serde_json::json!({ "file": cli.shader.display().to_string() }),
+            format!(
+                "skipping `{}`, a library module without entry points",
+                cli.shader.display()
+            ),
+        );
+        return Ok(());
+    }
+
+    let capabilities = Capabilities::all();
+    if let Some(path) = &cli.expect_bindings {
+        layout::check_expected_bindings(&composed, path)?;
+    }
+
+    if cli.print_workgroup_size {
+        for entry_point in composed
+            .entry_points
+            .iter()
+            .filter(|ep| ep.stage == ShaderStage::Compute)
+        {
+            let [x, y, z] = entry_point.workgroup_size;
+            println!("{}: [{x}, {y}, {z}]", entry_point.name);
+        }
+        return Ok(());
+    }
+
+    if cli.list_entry_points {
+        for entry_point in composed.entry_points.iter() {
+            match entry_point.stage {
+                ShaderStage::Compute => {
+                    let [x, y, z] = entry_point.workgroup_size;
+                    println!("{}: compute [{x}, {y}, {z}]", entry_point.name);
+                }
+                stage => println!("{}: {}", entry_point.name, stage_name(stage)),
+            }
+        }
+        return Ok(());
+    }
+
+    if cli.report_capabilities_per_entry {
+        // entry points are stripped from a copy of the module, which requires it to be valid
+        naga::valid::Validator::new(naga::valid::ValidationFlags::all(), capabilities)
+            .validate(&composed)
+            .map_err(|e| {
+                Error::compose(format!(
+                    "failed to validate composed module: {}",
+                    error_chain(&e)
+                ))
+            })?;
+        for (index, entry_point) in composed.entry_points.iter().enumerate() {
+            let required = capabilities::required_capabilities(&composed, index);
+            match required.is_empty() {
+                true => println!("{}: none", entry_point.name),
+                false => println!("{}: {}", entry_point.name, required.join(", ")),
+            }
+        }
+        return Ok(());
+    }
+
+    let format_outputs: Vec<_> = [
+        (OutputFormat::Wgsl, &cli.output_wgsl),
+        (OutputFormat::Glsl, &cli.output_glsl),
+        (OutputFormat::Spirv, &cli.output_spv),
+        (OutputFormat::Naga, &cli.output_naga),
+    ]
+    .into_iter()
+    .filter_map(|(format, path)| Some((format, path.clone()?)))
+    .collect();
+    let mut validated = Validated::default();
+    let results = if format_outputs.is_empty() {
+        write_format(cli, composed, composition, &mut validated, &mut watchdog)?
+    } else {
+        // each format is written from the same composed module, validated once per set of capabilities
+        let mut results = Vec::new();
+        for (format, path) in format_outputs {
+            let format_cli = Cli {
+                output: Some(path),
+                format: Some(format),
+                ..cli.clone()
+            };
+            results.extend(write_format(
+                &format_cli,
+                composed.clone(),
+                composition,
+                &mut validated,
+                &mut watchdog,
+            )?);
+        }
+        results
+    };
+    if cli.json_result {
+        print_json_result(results);
+    }
+    log::compiled();
+    Ok(())
+}
+
+/// Validation results for a composed module, kept so that writing it in several formats validates it once.
+#[derive(Default)]
+struct Validated {
+    /// With all capabilities, or unchecked when final validation is skipped
+    all: Option<naga::valid::ModuleInfo>,
+    /// With the capabilities of the target GLSL version
+    glsl: Option<naga::valid::ModuleInfo>,
+}
+
+/// The validation result in `slot`, validating with `validate` the first time it is needed.
+fn validate_once(
+    slot: &mut Option<naga::valid::ModuleInfo>,
+    validate: impl FnOnce() -> error::Result<naga::valid::ModuleInfo>,
+) -> error::Result<&naga::valid::ModuleInfo> {
+    if slot.is_none() {
+        *slot = Some(validate()?);
+    }
+    Ok(slot.as_ref().unwrap())
+}
+
+/// Validate a module, unless `validated` already holds the result, and write it in one output format.
+/// `watchdog` is disarmed once validation is done.
+fn write_format(
+    cli: &Cli,
+    mut composed: naga::Module,
+    composition: &Composition,
+    validated: &mut Validated,
+    watchdog: &mut Option<Watchdog>,
+) -> error::Result<Vec<serde_json::Value>> {
+    let capabilities = Capabilities::all();
+    let output_format = cli.format.clone().unwrap_or_else(|| {
+        cli.output_path()
+            .and_then(|path| path.extension().map(|o| o.to_string_lossy().into_owned()))
+            .and_then(|ext| match ext.trim().to_lowercase().as_str() {
+                "wgsl" => Some(OutputFormat::Wgsl),
+                "frag" | "vert" | "comp" => Some(OutputFormat::Glsl),
+                "json" => Some(OutputFormat::Naga),
+                "spv" | "bin" => Some(OutputFormat::Spirv),
+                _ => None,
+            })
+            .unwrap_or(OutputFormat::Wgsl)
+    });
+
+    if cli.strip_entry_points {
+        if let OutputFormat::Glsl | OutputFormat::Spirv = output_format {
+            return Err(Error::args(
+                "`--strip-entry-points` requires WGSL or NAGA output, as GLSL and SPIR-V need an entry point",
+            ));
+        }
+        composed.entry_points.clear();
+    }
+
+    if cli.sort_ir && !matches!(output_format, OutputFormat::Naga) {
+        return Err(Error::args("`--sort-ir` requires NAGA output"));
+    }
+
+    let glsl_options = cli.glsl_options()?;
+
+    // validate against what the backend can express, so unsupported features are reported up front
+    // `None` if final validation was skipped and the output doesn't need the analysis
+    let info = match output_format {
+        OutputFormat::Glsl | OutputFormat::Spirv if cli.skip_final_validation => {
+            return Err(Error::args(
+                "`--skip-final-validation` requires WGSL or NAGA output, as the GLSL and SPIR-V backends need a validated module",
+            ))
+        }
+        OutputFormat::Naga if cli.skip_final_validation => None,
+        // the WGSL backend still needs expression types, which an unchecked pass provides
+        OutputFormat::Wgsl if cli.skip_final_validation => Some(validate_once(&mut validated.all, || {
+            naga::valid::Validator::new(naga::valid::ValidationFlags::empty(), capabilities)
+                .validate(&composed)
+                .map_err(|e| {
+                    Error::compose(format!(
+                        "failed to analyze composed module: {}",
+                        error_chain(&e)
+                    ))
+                })
+        })?),
+        OutputFormat::Glsl => Some(validate_once(&mut validated.glsl, || {
+            naga::valid::Validator::new(
+                naga::valid::ValidationFlags::all(),
+                glsl_capabilities(glsl_options.version),
             )
-            .map_err(ComposerErrorInner::GlslBackError)
-            .unwrap();
+            .validate(&composed)
+            .map_err(|e| {
+                Error::compose(format!(
+                    "shader uses features unsupported by GLSL {}: {}",
+                    glsl_options.version,
+                    error_chain(&e)
+                ))
+            })
+        })?),
+        _ => Some(validate_once(&mut validated.all, || {
+            naga::valid::Validator::new(naga::valid::ValidationFlags::all(), capabilities)
+                .validate(&composed)
+                .map_err(|e| {
+                    Error::compose(format!(
+                        "failed to validate composed module: {}",
+                        error_chain(&e)
+                    ))
+                })
+        })?),
+    };
+    *watchdog = None;
+    let required_info =
+        || info.ok_or_else(|| Error::args("this output format requires final validation"));
 
-            writer
-                .write()
-                .map_err(ComposerErrorInner::GlslBackError)
-                .unwrap();
-            target.write_all(string.as_bytes()).unwrap();
+    if cli.json_result && cli.output_path().is_none() {
+        return Err(Error::args(
+            "`--json-result` writes the result to stdout, so the output must be written to a file with `--output`",
+        ));
+    }
+    if cli.sidecar && cli.output_path().is_none() {
+        return Err(Error::args(
+            "`--sidecar` is written next to the output file, so the output must be written to a file with `--output`",
+        ));
+    }
+    let mut results = Vec::new();
+
+    if cli.all_entry_points {
+        if let OutputFormat::Glsl = output_format {
+            // glsl requires a single stage per file, so write each entry point separately
+            let Some(output) = cli.output_path() else {
+                return Err(Error::args(
+                    "`--all-entry-points` with GLSL output requires `--output`",
+                ));
+            };
+            if cli.diff_against.is_some() {
+                return Err(Error::args(
+                    "`--diff-against` compares a single output, not one per entry point",
+                ));
+            }
+            for entry_point in composed.entry_points.iter() {
+                let path = entry_output_path(
+                    output,
+                    &cli.shader,
+                    cli.name_template
+                        .as_deref()
+                        .unwrap_or("{stem}.{entry}.{ext}"),
+                    entry_point,
+                    None,
+                    stage_extension(entry_point.stage),
+                );
+                check_glsl_locations(&composed, entry_point, glsl_options.version)?;
+                let output = cli.text_output(glsl_output(
+                    &composed,
+                    required_info()?,
+                    &glsl_options,
+                    cli.bounds_check_policies(),
+                    entry_point,
+                    cli.glsl_entry_name.as_deref(),
+                    &cli.glsl_extensions,
+                )?);
+                check_output_size(cli, Some(&path), output.len())?;
+                create_tree_output_dir(cli, &path)?;
+                write_output_file(&path, output.as_bytes(), cli.atomic_output)?;
+                if cli.sidecar {
+                    write_sidecar(cli, &path, composition)?;
+                }
+                if cli.print_summary {
+                    print_summary(Some(&path), output.as_bytes());
+                }
+                results.push(output_result(
+                    &output_format,
+                    &path,
+                    Some(entry_point),
+                    output.len(),
+                ));
+            }
+            return Ok(results);
         }
-        OutputFormat::Spirv => {
-            let vec = naga::back::spv::write_vec(
+    }
+
+    // glsl output files carry their stage in the extension
+    let output_stage = cli
+        .output_path()
+        .and_then(|path| path.extension())
+        .and_then(|ext| parse_stage(&ext.to_string_lossy()).ok());
+    let stage = cli.stage.or_else(|| match output_format {
+        OutputFormat::Glsl if cli.entry_point.is_none() && cli.entry_index.is_none() => {
+            output_stage
+        }
+        _ => None,
+    });
+    // WGSL and NAGA output keep every entry point, so there is nothing to select for them
+    let entry_point = match output_format {
+        OutputFormat::Wgsl | OutputFormat::Naga => None,
+        OutputFormat::Glsl | OutputFormat::Spirv => Some(match cli.entry_index {
+            Some(index) => composed.entry_points.get(index).ok_or_else(|| {
+                Error::args(format!(
+                    "entry point index {index} is out of range, the shader has {} entry points",
+                    composed.entry_points.len()
+                ))
+            })?,
+            None => {
+                // glsl and spv output contain a single entry point, which shouldn't be picked arbitrarily
+                let single = !matches!(output_format, OutputFormat::Spirv) || !cli.all_entry_points;
+                if single && cli.entry_point.is_none() {
+                    check_unambiguous_entry_point(&composed, stage)?;
+                }
+                select_entry_point(&composed, cli.entry_point.as_deref(), stage)?
+            }
+        }),
+    };
+    let required_entry_point = || {
+        entry_point.ok_or_else(|| Error::args("an entry point is required for this output format"))
+    };
+
+    // check the chosen entry point against the stages implied by the input and output file extensions
+    let input_stage = match composition.shader_type {
+        Some(ShaderType::GlslVertex) => Some(ShaderStage::Vertex),
+        Some(ShaderType::GlslFragment) => Some(ShaderStage::Fragment),
+        Some(ShaderType::Wgsl) | None => None,
+    };
+    for (path, extension_stage) in [
+        (Some(cli.shader.as_path()), input_stage),
+        (cli.output_path(), output_stage),
+    ] {
+        let (Some(entry_point), Some(path), Some(extension_stage)) =
+            (entry_point, path, extension_stage)
+        else {
+            continue;
+        };
+        if extension_stage == entry_point.stage {
+            continue;
+        }
+        let message = format!(
+            "entry point `{}` is a {} shader, but `{}` has a {} shader extension",
+            entry_point.name,
+            stage_name(entry_point.stage),
+            path.display(),
+            stage_name(extension_stage)
+        );
+        if cli.strict {
+            return Err(Error::args(message));
+        }
+        log::warn_at(Some(Location::file(path.display())), message);
+    }
+
+    // a sorted copy, as the validation info and entry point refer to the module's original order
+    let sorted;
+    let serialized = match cli.sort_ir {
+        true => {
+            let mut module = composed.clone();
+            sort::sort_module(&mut module);
+            sorted = module;
+            &sorted
+        }
+        false => &composed,
+    };
+
+    let output = match output_format {
+        OutputFormat::Wgsl => {
+            let wgsl = naga::back::wgsl::write_string(
+                &composed,
+                required_info()?,
+                cli.wgsl_writer_flags(),
+            )
+            .map_err(|e| backend_error("WGSL", &e))?;
+            if cli.roundtrip_check {
+                roundtrip_check_wgsl(&wgsl, capabilities)?;
+            }
+            let wgsl = if cli.source_map {
+                format!(
+                    "{}{wgsl}",
+                    source_map_header(&cli.shader, &composition.modules, composition.shader_defs)
+                )
+            } else {
+                wgsl
+            };
+            cli.text_output(wgsl).into_bytes()
+        }
+        OutputFormat::Glsl => {
+            let entry_point = required_entry_point()?;
+            check_glsl_locations(&composed, entry_point, glsl_options.version)?;
+            cli.text_output(glsl_output(
                 &composed,
+                required_info()?,
+                &glsl_options,
+                cli.bounds_check_policies(),
+                entry_point,
+                cli.glsl_entry_name.as_deref(),
+                &cli.glsl_extensions,
+            )?)
+            .into_bytes()
+        }
+        OutputFormat::Spirv => {
+            let entry_point = required_entry_point()?;
+            let pipeline_options = naga::back::spv::PipelineOptions {
+                shader_stage: entry_point.stage,
+                entry_point: entry_point.name.clone(),
+            };
+            // naga only omits unused items when writing a single entry point, so to keep them the
+            // whole module is written with the other entry points removed
+            let keep_unused = cli.spv_keep_unused && !cli.all_entry_points;
+            let prepared;
+            let module = if cli.spv_bindings.is_empty() && !cli.spv_debug && !keep_unused {
+                &composed
+            } else {
+                let mut module = composed.clone();
+                spv::remap_bindings(&mut module, &cli.spv_bindings)?;
+                if cli.spv_debug {
+                    spv::main_shader_spans(&mut module, composition.source.len());
+                }
+                if keep_unused {
+                    module
+                        .entry_points
+                        .retain(|ep| ep.name == entry_point.name && ep.stage == entry_point.stage);
+                }
+                prepared = module;
+                &prepared
+            };
+            let revalidated = match keep_unused {
+                true => Some(
+                    naga::valid::Validator::new(naga::valid::ValidationFlags::all(), capabilities)
+                        .validate(module)
+                        .map_err(|e| {
+                            Error::compose(format!(
+                                "failed to validate composed module: {}",
+                                error_chain(&e)
+                            ))
+                        })?,
+                ),
+                false => None,
+            };
+            let module_info = match &revalidated {
+                Some(info) => info,
+                None => required_info()?,
+            };
+            let spv_output = |lang_version| -> error::Result<Vec<u8>> {
+                let mut options = cli.spv_options();
+                if cli.spv_debug {
+                    options.flags |= naga::back::spv::WriterFlags::DEBUG;
+                    options.debug_info = Some(naga::back::spv::DebugInfo {
+                        source_code: composition.source,
+                        file_name: &cli.shader,
+                    });
+                }
+                if let Some(lang_version) = lang_version {
+                    options.lang_version = lang_version;
+                }
+                let mut words = naga::back::spv::write_vec(
+                    module,
+                    module_info,
+                    &options,
+                    (!cli.all_entry_points && !keep_unused).then_some(&pipeline_options),
+                )
+                .map_err(|e| backend_error("SPV", &e))?;
+                spv::inject_declarations(&mut words, &cli.spv_capabilities, &cli.spv_extensions);
+                Ok(words.iter().flat_map(|long| long.to_be_bytes()).collect())
+            };
+
+            if cli.spv_versions.len() > 1 {
+                // write a file per version, carrying on past failures so that all incompatible versions are reported
+                let Some(output) = cli.output_path() else {
+                    return Err(Error::args("multiple `--spv-version`s require `--output`"));
+                };
+                if cli.diff_against.is_some() {
+                    return Err(Error::args(
+                        "`--diff-against` compares a single output, not one per SPIR-V version",
+                    ));
+                }
+                let template = cli
+                    .name_template
+                    .as_deref()
+                    .unwrap_or("{stem}.{entry}.spv{version}.{ext}");
+                let mut failed = Vec::new();
+                for &(major, minor) in cli.spv_versions.iter() {
+                    let version = format!("{major}.{minor}");
+                    let path = entry_output_path(
+                        output,
+                        &cli.shader,
+                        template,
+                        entry_point,
+                        Some(&version),
+                        "spv",
+                    );
+                    let result = spv_output(Some((major, minor))).and_then(|output| {
+                        check_output_size(cli, Some(&path), output.len())?;
+                        create_tree_output_dir(cli, &path)?;
+                        write_output_file(&path, &output, cli.atomic_output)?;
+                        if cli.sidecar {
+                            write_sidecar(cli, &path, composition)?;
+                        }
+                        Ok(output)
+                    });
+                    match result {
+                        Ok(output) => {
+                            if cli.print_summary {
+                                print_summary(Some(&path), &output);
+                            }
+                            results.push(output_result(
+                                &output_format,
+                                &path,
+                                Some(entry_point),
+                                output.len(),
+                            ));
+                        }
+                        Err(e) => {
+                            log::warn_at(None, format!("SPIR-V {version}: {}", e.message));
+                            failed.push(version);
+                        }
+                    }
+                }
+                if !failed.is_empty() {
+                    return Err(Error::backend(format!(
+                        "failed to write SPIR-V for version(s) {}",
+                        failed.join(", ")
+                    )));
+                }
+                return Ok(results);
+            }
+
+            spv_output(cli.spv_versions.first().copied())?
+        }
+        OutputFormat::Naga if cli.embed_source => {
+            let mut envelope = composition_json(cli, composition);
+            envelope["source"] = composition.original_source.into();
+            envelope["module"] =
+                serde_json::to_value(serialized).map_err(|e| backend_error("NAGA", &e))?;
+            serde_json::to_vec(&envelope).map_err(|e| backend_error("NAGA", &e))?
+        }
+        OutputFormat::Naga => {
+            serde_json::to_vec(serialized).map_err(|e| backend_error("NAGA", &e))?
+        }
+    };
+
+    // compare before writing, as the previous artifact may be the output file itself
+    if let Some(previous) = &cli.diff_against {
+        diff_against(previous, &output_format, &output)?;
+    }
+
+    // only create the output file once the output has been successfully generated
+    check_output_size(cli, cli.output_path(), output.len())?;
+    match cli.output_path() {
+        Some(path) => {
+            create_tree_output_dir(cli, path)?;
+            write_output_file(path, &output, cli.atomic_output)?;
+            if cli.sidecar {
+                write_sidecar(cli, path, composition)?;
+            }
+        }
+        None => stdout()
+            .write_all(&output)
+            .map_err(|e| Error::io(format!("failed to write to stdout: {e}")))?,
+    }
+
+    if cli.print_summary {
+        print_summary(cli.output_path(), &output);
+    }
+
+    if let Some(path) = cli.output_path() {
+        results.push(output_result(
+            &output_format,
+            path,
+            entry_point,
+            output.len(),
+        ));
+    }
+
+    Ok(results)
+}
+
+/// Compare generated output with the artifact at `path`, logging a unified diff for text formats
+/// and whether anything changed for the others.
+fn diff_against(path: &Path, format: &OutputFormat, output: &[u8]) -> error::Result<()> {
+    let previous = std::fs::read(path).map_err(|e| {
+        Error::io(format!(
+            "failed to read previous output `{}`: {e}",
+            path.display()
+        ))
+    })?;
+    let diff = match format {
+        OutputFormat::Wgsl | OutputFormat::Glsl => diff::unified_diff(
+            &path.display().to_string(),
+            "generated output",
+            &String::from_utf8_lossy(&previous),
+            &String::from_utf8_lossy(output),
+        ),
+        // naga and SPIR-V output isn't line based, so a diff wouldn't be readable
+        OutputFormat::Naga | OutputFormat::Spirv => {
+            (previous != output).then(|| format!("output differs from `{}`", path.display()))
+        }
+    };
+    match diff {
+        Some(diff) => diff.lines().for_each(log::info),
+        None => log::info(format!("output is unchanged from `{}`", path.display())),
+    }
+    Ok(())
+}
+
+/// Fail if an artifact is larger than `--max-output-bytes`.
+fn check_output_size(cli: &Cli, path: Option<&Path>, len: usize) -> error::Result<()> {
+    match cli.max_output_bytes {
+        Some(max) if len > max => {
+            let target = path.map_or("stdout".to_owned(), |path| format!("`{}`", path.display()));
+            Err(Error::compose(format!(
+                "output for {target} is {len} bytes, exceeding the limit of {max} bytes"
+            )))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Exits the process if it isn't dropped within a time limit, as composition and validation can't be cancelled.
+struct Watchdog {
+    // dropping the sender wakes the watchdog thread so that it can finish
+    _disarm: std::sync::mpsc::Sender<()>,
+}
+
+impl Watchdog {
+    /// Start the watchdog, which exits after `seconds` unless dropped first. As the process ends
+    /// there, the diagnostics file for `--diagnostics-out` is written before exiting.
+    fn start(seconds: f64, diagnostics_out: Option<PathBuf>) -> Self {
+        let (disarm, disarmed) = std::sync::mpsc::channel::<()>();
+        std::thread::spawn(move || {
+            let limit = std::time::Duration::from_secs_f64(seconds);
+            if let Err(std::sync::mpsc::RecvTimeoutError::Timeout) = disarmed.recv_timeout(limit) {
+                let e = Error::new(
+                    ErrorKind::Timeout,
+                    format!("composition and validation didn't finish within {seconds} seconds"),
+                );
+                log::record(Diagnostic {
+                    severity: Severity::Error,
+                    message: e.message.clone(),
+                    location: None,
+                });
+                if let Some(path) = &diagnostics_out {
+                    if let Err(e) = write_diagnostics(path) {
+                        e.print();
+                    }
+                }
+                e.print();
+                log::summary();
+                exit(e.kind.exit_code());
+            }
+        });
+        Self { _disarm: disarm }
+    }
+}
+
+/// Describe one written artifact for `--json-result`.
+fn output_result(
+    format: &OutputFormat,
+    path: &Path,
+    entry_point: Option<&EntryPoint>,
+    len: usize,
+) -> serde_json::Value {
+    serde_json::json!({
+        "format": format.name(),
+        "path": path.display().to_string(),
+        "entry_point": entry_point.map(|entry_point| &entry_point.name),
+        "bytes": len,
+    })
+}
+
+/// Write the `--json-result` summary of a successful run to stdout, as a single object covering
+/// every format written.
+fn print_json_result(outputs: Vec<serde_json::Value>) {
+    let result = serde_json::json!({
+        "success": true,
+        "outputs": outputs,
+    });
+    println!("{result}");
+}
+
+/// Print every shader def referenced or declared by the main shader and the scanned modules,
+/// along with the modules that use it.
+fn print_def_references(shader: &Path, source: &str, includes: &HashMap<String, IncludedModule>) {
+    let preprocessor = naga_oil::compose::preprocess::Preprocessor::default();
+    let mut references: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+    let shader_name = shader.display().to_string();
+    let modules = std::iter::once((&shader_name, source)).chain(
+        includes
+            .iter()
+            .map(|(name, module)| (name, module.source.as_str())),
+    );
+    for (name, source) in modules {
+        // modules that fail to preprocess are reported properly when composing
+        let Ok(metadata) = preprocessor.get_preprocessor_metadata(source, true) else {
+            continue;
+        };
+        for def in metadata
+            .effective_defs
+            .into_iter()
+            .chain(metadata.defines.into_keys())
+        {
+            references.entry(def).or_default().insert(name.clone());
+        }
+    }
+    for (def, modules) in references {
+        let modules: Vec<_> = modules.into_iter().collect();
+        println!("{def} -> [{}]", modules.join(", "));
+    }
+}
+
+/// Report the size and a short content hash of an artifact.
+fn print_summary(path: Option<&Path>, output: &[u8]) {
+    let target = path.map_or("stdout".to_owned(), |path| format!("`{}`", path.display()));
+    log::event(
+        "output_written",
+        serde_json::json!({
+            "file": path.map(|path| path.display().to_string()),
+            "bytes": output.len(),
+            "hash": format!("{:016x}", content_hash(output)),
+        }),
+        format!(
+            "wrote {} bytes to {target} (hash {:016x})",
+            output.len(),
+            content_hash(output)
+        ),
+    );
+}
+
+/// A stable 64-bit FNV-1a hash of `bytes`, for detecting whether output has changed between runs.
+fn content_hash(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Create the directory of an output file for `--compile-tree`, which mirrors the input tree but only
+/// creates the directories that receive an artifact.
+fn create_tree_output_dir(cli: &Cli, path: &Path) -> error::Result<()> {
+    match (&cli.compile_tree, path.parent()) {
+        (Some(_), Some(directory)) => std::fs::create_dir_all(directory).map_err(|e| {
+            Error::io(format!(
+                "failed to create directory for `{}`: {e}",
+                path.display()
+            ))
+        }),
+        _ => Ok(()),
+    }
+}
+
+/// Write an output file, optionally via a temporary sibling file that is renamed into place
+/// so that readers never observe a partially written file.
+fn write_output_file(path: &Path, contents: &[u8], atomic: bool) -> error::Result<()> {
+    let result = if atomic {
+        let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+        let temp_path = path.with_file_name(format!(".{file_name}.{}.tmp", std::process::id()));
+        let result =
+            std::fs::write(&temp_path, contents).and_then(|_| std::fs::rename(&temp_path, path));
+        if result.is_err() {
+            let _ = std::fs::remove_file(&temp_path);
+        }
+        result
+    } else {
+        std::fs::write(path, contents)
+    };
+
+    result.map_err(|e| {
+        Error::io(format!(
+            "failed to write output file `{}`: {e}",
+            path.display()
+        ))
+    })
+}
+
+/// A comment block describing the inputs that produced the output.
+fn source_map_header(
+    shader: &Path,
+    modules: &[(&str, &Path)],
+    shader_defs: &HashMap<String, ShaderDefValue>,
+) -> String {
+    let mut header = format!("// generated by naga_oil_cli from `{}`\n", shader.display());
+
+    if !modules.is_empty() {
+        header.push_str("// modules:\n");
+        for (name, path) in modules {
+            header.push_str(&format!("//   {name}: `{}`\n", path.display()));
+        }
+    }
+
+    if !shader_defs.is_empty() {
+        let mut defs: Vec<_> = shader_defs.iter().collect();
+        defs.sort_by_key(|(name, _)| name.as_str());
+        header.push_str("// defs:\n");
+        for (name, value) in defs {
+            header.push_str(&format!("//   {name}={}\n", def_value_string(value)));
+        }
+    }
+
+    header.push('\n');
+    header
+}
+
+/// Format a def value in the syntax accepted by `--defs`.
+fn def_value_string(value: &ShaderDefValue) -> String {
+    match value {
+        ShaderDefValue::Bool(value) => value.to_string(),
+        ShaderDefValue::Int(value) => value.to_string(),
+        ShaderDefValue::UInt(value) => format!("{value}u"),
+    }
+}
+
+/// Check that generated WGSL parses and validates.
+fn roundtrip_check_wgsl(wgsl: &str, capabilities: Capabilities) -> error::Result<()> {
+    let module = naga::front::wgsl::parse_str(wgsl).map_err(|e| {
+        Error::backend(format!(
+            "generated WGSL failed to parse:\n{}",
+            e.emit_to_string(wgsl)
+        ))
+    })?;
+    naga::valid::Validator::new(naga::valid::ValidationFlags::all(), capabilities)
+        .validate(&module)
+        .map_err(|e| {
+            Error::backend(format!(
+                "generated WGSL failed to validate:\n{}",
+                e.emit_to_string(wgsl)
+            ))
+        })?;
+    Ok(())
+}
+
+fn backend_error(backend: &str, e: &dyn std::error::Error) -> Error {
+    Error::backend(format!(
+        "failed to write {backend} output: {}",
+        error_chain(e)
+    ))
+}
+
+fn glsl_output(
+    module: &naga::Module,
+    info: &naga::valid::ModuleInfo,
+    options: &naga::back::glsl::Options,
+    policies: BoundsCheckPolicies,
+    entry_point: &EntryPoint,
+    entry_name: Option<&str>,
+    extensions: &[String],
+) -> error::Result<String> {
+    let mut string = String::new();
+    let pipeline_options = naga::back::glsl::PipelineOptions {
+        shader_stage: entry_point.stage,
+        entry_point: entry_point.name.clone(),
+        multiview: None,
+    };
+    let result = naga::back::glsl::Writer::new(
+        &mut string,
+        module,
+        info,
+        options,
+        &pipeline_options,
+        policies,
+    )
+    .and_then(|mut writer| writer.write().map(|_| ()));
+
+    result.map_err(|e| backend_error(&format!("GLSL {}", options.version), &e))?;
+
+    if let Some(name) = entry_name {
+        // naga 0.14's GLSL writer always names the entry point function `main` and has no option to
+        // change it, so the name is patched into the generated text. naga reserves `main` for the entry
+        // point, renaming any other function with that name, and writes its definition at the start of a line
+        let Some(index) = string.find("\nvoid main(") else {
+            return Err(Error::backend(
+                "can't rename the GLSL entry point: no `void main(` definition in the generated GLSL",
+            ));
+        };
+        let start = index + "\nvoid ".len();
+        let name = name.replace("{entry}", &entry_point.name);
+        string.replace_range(start..start + "main".len(), &name);
+    }
+
+    let declarations: String = extensions
+        .iter()
+        .filter(|name| !string.contains(&format!("#extension {name} ")))
+        .map(|name| format!("#extension {name} : require\n"))
+        .collect();
+    // naga always starts with the `#version` line
+    Ok(match string.split_once('\n') {
+        Some((version, rest)) if !declarations.is_empty() => {
+            format!("{version}\n{declarations}{rest}")
+        }
+        _ => string,
+    })
+}
+
+/// Report inter-stage varyings (vertex outputs and fragment inputs) whose `@location` can't be written
+/// in the target GLSL version, which only supports `layout(location = N)` on these from GLSL 410 and
+/// GLSL ES 310. naga would name them after their location instead, leaving the interface to be matched
+/// by name, which breaks when the other stage is compiled from a different shader.
+fn check_glsl_locations(
+    module: &naga::Module,
+    entry_point: &EntryPoint,
+    version: naga::back::glsl::Version,
+) -> error::Result<()> {
+    let explicit_locations = match version {
+        naga::back::glsl::Version::Desktop(version) => version >= 410,
+        naga::back::glsl::Version::Embedded { version, .. } => version >= 310,
+    };
+    if explicit_locations {
+        return Ok(());
+    }
+
+    let mut locations = Vec::new();
+    let mut collect = |binding: Option<&naga::Binding>, ty: naga::Handle<naga::Type>| match binding
+    {
+        Some(naga::Binding::Location { location, .. }) => locations.push(*location),
+        Some(naga::Binding::BuiltIn(_)) => (),
+        None => {
+            if let naga::TypeInner::Struct { members, .. } = &module.types[ty].inner {
+                for member in members {
+                    if let Some(naga::Binding::Location { location, .. }) = &member.binding {
+                        locations.push(*location);
+                    }
+                }
+            }
+        }
+    };
+    let function = &entry_point.function;
+    let varyings = match entry_point.stage {
+        ShaderStage::Vertex => "outputs",
+        ShaderStage::Fragment => "inputs",
+        ShaderStage::Compute => return Ok(()),
+    };
+    match entry_point.stage {
+        ShaderStage::Vertex => {
+            if let Some(result) = &function.result {
+                collect(result.binding.as_ref(), result.ty);
+            }
+        }
+        _ => {
+            for argument in function.arguments.iter() {
+                collect(argument.binding.as_ref(), argument.ty);
+            }
+        }
+    }
+    if locations.is_empty() {
+        return Ok(());
+    }
+
+    locations.sort();
+    let locations: Vec<_> = locations.iter().map(ToString::to_string).collect();
+    Err(Error::args(format!(
+        "GLSL {version} can't declare the locations of the {varyings} of entry point `{}` (@location {}), target GLSL 410 or GLSL ES 310 or later",
+        entry_point.name,
+        locations.join(", ")
+    )))
+}
+
+/// The capabilities that can be expressed by the given GLSL version.
+fn glsl_capabilities(version: naga::back::glsl::Version) -> Capabilities {
+    match version {
+        naga::back::glsl::Version::Desktop(_) => Capabilities::all(),
+        naga::back::glsl::Version::Embedded { version, .. } => {
+            let mut capabilities = Capabilities::all()
+                - Capabilities::FLOAT64
+                - Capabilities::CLIP_DISTANCE
+                - Capabilities::CULL_DISTANCE
+                - Capabilities::PRIMITIVE_INDEX
+                - Capabilities::RAY_QUERY
+                - Capabilities::DUAL_SOURCE_BLENDING
+                - Capabilities::STORAGE_TEXTURE_16BIT_NORM_FORMATS
+                - Capabilities::SAMPLED_TEXTURE_AND_STORAGE_BUFFER_ARRAY_NON_UNIFORM_INDEXING
+                - Capabilities::UNIFORM_BUFFER_AND_STORAGE_TEXTURE_ARRAY_NON_UNIFORM_INDEXING
+                - Capabilities::SAMPLER_NON_UNIFORM_INDEXING;
+            if version < 320 {
+                capabilities -=
+                    Capabilities::CUBE_ARRAY_TEXTURES | Capabilities::MULTISAMPLED_SHADING;
+            }
+            capabilities
+        }
+    }
+}
+
+/// Format an error along with its chain of sources.
+fn error_chain(e: &dyn std::error::Error) -> String {
+    let mut message = e.to_string();
+    let mut source = e.source();
+    while let Some(inner) = source {
+        message.push_str(&format!(": {inner}"));
+        source = inner.source();
+    }
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn def_value_u32_suffix() {
+        assert!(matches!(parse_def_value("0u"), Ok(ShaderDefValue::UInt(0))));
+        assert!(matches!(
+            parse_def_value("4294967295u"),
+            Ok(ShaderDefValue::UInt(u32::MAX))
+        ));
+        assert!(parse_def_value("-1u").is_err());
+        assert!(parse_def_value("foou").is_err());
+    }
+
+    #[test]
+    fn self_import() {
+        let source = "#define_import_path util\n#import util\nfn f() { util::f(); }\n";
+        let (_, reqs, defines) = naga_oil::compose::get_preprocessor_data(source);
+        let includes = HashMap::from([(
+            "util".to_owned(),
+            IncludedModule {
+                reqs: import_names(reqs),
+                path: PathBuf::from("util.wgsl"),
+                language: ShaderLanguage::Wgsl,
+                source: source.to_owned(),
+                defines,
+                shadowed: Vec::new(),
+            },
+        )]);
+        let e = add_module_with_imports(
+            &mut Composer::default(),
+            &includes,
+            "util",
+            &mut Vec::new(),
+            &mut Vec::new(),
+            false,
+        )
+        .unwrap_err();
+        assert_eq!(e.kind, ErrorKind::Compose);
+        assert_eq!(e.message, "module util imports itself");
+    }
+
+    #[test]
+    fn path_import_with_alias() {
+        let mut source =
+            "// see \"util.wgsl\"\n#import \"util.wgsl\" as u\nfn f() { u::g(); }\n".to_owned();
+        let mut reqs = vec!["\"util.wgsl\"".to_owned()];
+        let names_by_path =
+            HashMap::from([(normalize_path(Path::new("util.wgsl")), "util".to_owned())]);
+        resolve_path_imports(&mut source, &mut reqs, None, &names_by_path);
+        assert_eq!(
+            source,
+            "// see \"util.wgsl\"\n#import util as u\nfn f() { u::g(); }\n"
+        );
+        assert_eq!(reqs, ["util"]);
+    }
+
+    #[test]
+    fn glsl_entry_name() {
+        let source = "fn main_color() -> vec4<f32> { return vec4(1.0); }\n@fragment fn fs() -> @location(0) vec4<f32> { return main_color(); }\n";
+        let module = naga::front::wgsl::parse_str(source).unwrap();
+        let info =
+            naga::valid::Validator::new(naga::valid::ValidationFlags::all(), Capabilities::all())
+                .validate(&module)
+                .unwrap();
+        let glsl = glsl_output(
+            &module,
+            &info,
+            &naga::back::glsl::Options::default(),
+            BoundsCheckPolicies::default(),
+            &module.entry_points[0],
+            Some("{entry}_main"),
+            &[],
+        )
+        .unwrap();
+        assert!(glsl.contains("void fs_main("));
+        assert!(!glsl.contains("void main("));
+    }
+
+    #[test]
+    fn glsl_locations() {
+        let source = "struct Varyings { @builtin(position) position: vec4<f32>, @location(0) color: vec4<f32>, @location(3) uv: vec2<f32> }\n\
+            @vertex fn vs() -> Varyings { return Varyings(vec4(0.0), vec4(1.0), vec2(0.5)); }\n\
+            @fragment fn fs(in: Varyings) -> @location(0) vec4<f32> { return in.color * in.uv.x; }\n";
+        let module = naga::front::wgsl::parse_str(source).unwrap();
+        let info =
+            naga::valid::Validator::new(naga::valid::ValidationFlags::all(), Capabilities::all())
+                .validate(&module)
+                .unwrap();
+        let options = naga::back::glsl::Options {
+            version: naga::back::glsl::Version::Desktop(450),
+            ..Default::default()
+        };
+        for entry_point in module.entry_points.iter() {
+            check_glsl_locations(&module, entry_point, options.version).unwrap();
+            let glsl = glsl_output(
+                &module,
                 &info,
-                &naga::back::spv::Options::default(),
-                Some(&naga::back::spv::PipelineOptions {
-                    shader_stage,
-                    entry_point,
-                }),
+                &options,
+                BoundsCheckPolicies::default(),
+                entry_point,
+                None,
+                &[],
             )
             .unwrap();
-            for long in vec.iter() {
-                for b in long.to_be_bytes() {
-                    target.write_all(&[b]).unwrap();
-                }
+            for location in ["layout(location = 0)", "layout(location = 3)"] {
+                assert!(glsl.contains(location), "{location} missing from\n{glsl}");
             }
+            assert!(check_glsl_locations(
+                &module,
+                entry_point,
+                naga::back::glsl::Version::Desktop(330)
+            )
+            .is_err());
         }
-        OutputFormat::Naga => target
-            .write_all(&serde_json::to_vec(&composed).unwrap())
-            .unwrap(),
     }
 }
@@ -0,0 +1,670 @@
+//! Discovery of the modules available for import.
+
+use std::{
+    collections::{HashMap, HashSet},
+    io::IsTerminal,
+    path::{Component, Path, PathBuf},
+    str::FromStr,
+};
+
+use naga_oil::compose::{ImportDefinition, ShaderDefValue, ShaderLanguage, ShaderType};
+
+use crate::{
+    error::{self, Error},
+    log::{self, Location},
+    transform_source, Cli,
+};
+
+/// A module found while scanning the include paths.
+#[derive(Clone)]
+pub struct IncludedModule {
+    pub reqs: Vec<String>,
+    pub path: PathBuf,
+    pub language: ShaderLanguage,
+    pub source: String,
+    /// Defs declared with `#define` in the module source.
+    pub defines: HashMap<String, ShaderDefValue>,
+    /// Paths of earlier definitions of the same name that this module replaced.
+    pub shadowed: Vec<PathBuf>,
+}
+
+/// Number of files scanned before `--progress` starts reporting, so small trees stay quiet.
+const PROGRESS_THRESHOLD: usize = 256;
+/// Number of files scanned between `--progress` updates.
+const PROGRESS_INTERVAL: usize = 64;
+
+/// Scan the include paths (and stdin, if requested) for modules, keyed by import name, adding the
+/// `packed` files other than the shader from `--unpack`.
+pub fn scan_includes(
+    cli: &Cli,
+    packed: Vec<PackedFile>,
+) -> error::Result<HashMap<String, IncludedModule>> {
+    let mut include = cli.include.clone();
+    for path in cli.include_from.iter() {
+        include.extend(read_include_list(path)?);
+    }
+    // the default of `.` only applies if no include paths (or packed modules) were given at all
+    let explicit = !cli.include_prefixed.is_empty() || cli.unpack.is_some();
+    let mut roots: Vec<_> = match include.is_empty() && explicit {
+        true => Vec::new(),
+        false => gather_paths(&include)?
+            .into_iter()
+            .map(|path| (None, path))
+            .collect(),
+    };
+    roots.extend(
+        cli.include_prefixed
+            .iter()
+            .map(|(prefix, path)| (Some(prefix.as_str()), path.clone())),
+    );
+    // each pending path is tagged with the include root it was found under, and the depth below that root
+    // of the entries it contains if it is a directory
+    let mut include_paths: Vec<_> = roots
+        .iter()
+        .enumerate()
+        .map(|(root, (_, path))| (root, 0, path.clone()))
+        .collect();
+    let mut includes = HashMap::new();
+    let show_progress = cli.progress && std::io::stderr().is_terminal();
+    let mut scanned = 0;
+
+    while let Some((root, depth, path)) = include_paths.pop() {
+        if path.is_dir() {
+            if cli.max_depth.is_some_and(|max_depth| depth > max_depth) {
+                continue;
+            }
+            let mut entries = std::fs::read_dir(&path)
+                .and_then(|entries| {
+                    entries
+                        .map(|entry| entry.map(|entry| entry.path()))
+                        .collect::<Result<Vec<_>, _>>()
+                })
+                .map_err(|e| {
+                    Error::io(format!(
+                        "failed to read directory `{}`: {e}",
+                        path.display()
+                    ))
+                })?;
+            // sort so that scanning (and duplicate resolution) doesn't depend on the filesystem's ordering
+            entries.sort_by(|a, b| b.cmp(a));
+            include_paths.extend(entries.into_iter().map(|entry| (root, depth + 1, entry)));
+        } else {
+            scanned += 1;
+            if show_progress && scanned >= PROGRESS_THRESHOLD && scanned % PROGRESS_INTERVAL == 0 {
+                log::progress(format!(
+                    "scanning: {scanned} files, {} modules",
+                    includes.len()
+                ));
+            }
+
+            let Some(language) = module_language(&path, &cli.module_lang, cli.default_include_lang)
+            else {
+                continue;
+            };
+
+            let source = std::fs::read_to_string(&path)
+                .map_err(|e| Error::io(format!("failed to read file `{}`: {e}", path.display())));
+            let source = match &cli.transform_cmd {
+                Some(command) => source.and_then(|source| transform_source(command, &path, source)),
+                None => source,
+            };
+            match source {
+                Err(e) => return Err(e),
+                Ok(source) if source.trim().is_empty() => {
+                    // placeholder or generated files with no content can't define a module
+                    log::event(
+                        "file_skipped",
+                        serde_json::json!({ "file": path.display().to_string() }),
+                        format!("skipping empty file `{}`", path.display()),
+                    );
+                }
+                Ok(source) => {
+                    let (name, reqs, defines) = naga_oil::compose::get_preprocessor_data(&source);
+                    let (prefix, root_path) = &roots[root];
+                    let name = name.unwrap_or_else(|| match prefix {
+                        Some(prefix) => {
+                            let relative = path.strip_prefix(root_path).unwrap_or(&path);
+                            format!(
+                                "\"{prefix}::{}\"",
+                                relative.to_string_lossy().replace("\\", "/")
+                            )
+                        }
+                        None => format!("\"{}\"", path.to_string_lossy().replace("\\", "/")),
+                    });
+                    let name = name
+                        .strip_prefix("\"./")
+                        .map(|name| format!("\"{name}"))
+                        .unwrap_or(name);
+                    log::event(
+                        "module_found",
+                        serde_json::json!({ "module": name, "file": path.display().to_string() }),
+                        format!("found {name}"),
+                    );
+                    if cli.check_naming && !name.starts_with('"') {
+                        check_naming(&name, &path, root_path, cli.naming_prefix.as_deref());
+                    }
+                    insert_module(
+                        &mut includes,
+                        name,
+                        IncludedModule {
+                            reqs: import_names(reqs),
+                            path,
+                            language,
+                            source,
+                            defines,
+                            shadowed: Vec::new(),
+                        },
+                        cli.isolate,
+                    );
+                    check_module_count(cli, includes.len())?;
+                }
+            };
+        }
+    }
+    log::clear_progress();
+
+    if cli.modules_stdin {
+        for (name, module) in read_stdin_modules(cli.transform_cmd.as_deref())? {
+            log::event(
+                "module_found",
+                serde_json::json!({ "module": name, "source": "stdin" }),
+                format!("found {name} (stdin)"),
+            );
+            // stdin modules deliberately override scanned ones, so replacing one isn't a duplicate
+            includes.insert(name, module);
+        }
+    }
+
+    for file in packed {
+        let language = module_language(&file.path, &cli.module_lang, cli.default_include_lang)
+            .ok_or_else(|| {
+                Error::args(format!(
+                    "can't determine the language of packed file `{}`",
+                    file.path.display()
+                ))
+            })?;
+        let source = match &cli.transform_cmd {
+            Some(command) => transform_source(command, &file.path, file.source)?,
+            None => file.source,
+        };
+        let (name, reqs, defines) = naga_oil::compose::get_preprocessor_data(&source);
+        let name = name.unwrap_or(format!(
+            "\"{}\"",
+            file.path.to_string_lossy().replace("\\", "/")
+        ));
+        log::event(
+            "module_found",
+            serde_json::json!({ "module": name, "file": file.path.display().to_string(), "source": "packed" }),
+            format!("found {name} (packed)"),
+        );
+        insert_module(
+            &mut includes,
+            name,
+            IncludedModule {
+                reqs: import_names(reqs),
+                path: file.path,
+                language,
+                source,
+                defines,
+                shadowed: Vec::new(),
+            },
+            cli.isolate,
+        );
+    }
+
+    check_module_count(cli, includes.len())?;
+    Ok(includes)
+}
+
+/// A file read from a packed file by `--unpack`.
+pub struct PackedFile {
+    pub path: PathBuf,
+    pub source: String,
+    /// Whether this is the shader to compile.
+    pub entry: bool,
+}
+
+/// Split a packed file into the files it contains. Each file starts with a `// === path ===` marker
+/// line, and the shader to compile is marked with `// === path === entry`, or is otherwise the first.
+pub fn unpack(path: &Path) -> error::Result<Vec<PackedFile>> {
+    let contents = std::fs::read_to_string(path).map_err(|e| {
+        Error::io(format!(
+            "failed to read packed file `{}`: {e}",
+            path.display()
+        ))
+    })?;
+
+    let mut files: Vec<PackedFile> = Vec::new();
+    for (index, line) in contents.lines().enumerate() {
+        let marker = line
+            .trim()
+            .strip_prefix("// ===")
+            .and_then(|marker| marker.split_once("==="));
+        match (marker, files.last_mut()) {
+            (Some((file_path, rest)), _) if !file_path.trim().is_empty() => {
+                let entry = match rest.trim() {
+                    "" => false,
+                    "entry" => true,
+                    other => {
+                        return Err(Error::args(format!(
+                            "{}:{}: unexpected `{other}` after marker, expected `entry` or nothing",
+                            path.display(),
+                            index + 1
+                        )))
+                    }
+                };
+                files.push(PackedFile {
+                    path: PathBuf::from(file_path.trim()),
+                    source: String::new(),
+                    entry,
+                });
+            }
+            (_, Some(file)) => {
+                file.source.push_str(line);
+                file.source.push('\n');
+            }
+            (_, None) if line.trim().is_empty() => (),
+            (_, None) => {
+                return Err(Error::args(format!(
+                    "{}:{}: expected a `// === path ===` marker before any content",
+                    path.display(),
+                    index + 1
+                )))
+            }
+        }
+    }
+
+    match files.iter().filter(|file| file.entry).count() {
+        0 => match files.first_mut() {
+            Some(file) => file.entry = true,
+            None => {
+                return Err(Error::args(format!(
+                    "packed file `{}` contains no `// === path ===` markers",
+                    path.display()
+                )))
+            }
+        },
+        1 => (),
+        _ => {
+            return Err(Error::args(format!(
+                "packed file `{}` marks more than one entry",
+                path.display()
+            )))
+        }
+    }
+    Ok(files)
+}
+
+/// Stop scanning once more than `--max-modules` modules have been found, as an include path probably
+/// points at a larger tree than intended.
+fn check_module_count(cli: &Cli, count: usize) -> error::Result<()> {
+    match cli.max_modules {
+        Some(max) if count > max => Err(Error::args(format!(
+            "found more than {max} modules while scanning the include paths; check that they are correct, or raise the limit with `--max-modules`"
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// Warn if a module's declared import path doesn't mirror its location under its include root,
+/// e.g. `prefix::pbr::lighting` for `pbr/lighting.wgsl`.
+fn check_naming(name: &str, path: &Path, root: &Path, prefix: Option<&str>) {
+    let relative = match path.strip_prefix(root) {
+        Ok(relative) if !relative.as_os_str().is_empty() => relative,
+        _ => Path::new(path.file_name().unwrap_or_default()),
+    };
+    let mut segments: Vec<_> = prefix.into_iter().map(str::to_owned).collect();
+    segments.extend(
+        relative
+            .with_extension("")
+            .components()
+            .map(|component| component.as_os_str().to_string_lossy().into_owned()),
+    );
+    let expected = segments.join("::");
+    if name != expected {
+        log::warn_at(
+            Some(Location::file(path.display())),
+            format!("module `{name}` should be named `{expected}` to match its path"),
+        );
+    }
+}
+
+/// Add a scanned module, replacing (and remembering) any earlier definition of the same name.
+/// Duplicates are reported immediately unless `isolate` is set, in which case they only matter
+/// if the module turns out to be needed.
+fn insert_module(
+    includes: &mut HashMap<String, IncludedModule>,
+    name: String,
+    mut module: IncludedModule,
+    isolate: bool,
+) {
+    if let Some(previous) = includes.remove(&name) {
+        if !isolate {
+            log::warn_at(
+                Some(Location::file(module.path.display())),
+                format!("duplicate definition for `{name}`"),
+            );
+        }
+        module.shadowed = previous.shadowed;
+        module.shadowed.push(previous.path);
+    }
+    includes.insert(name, module);
+}
+
+/// Read the include paths listed in an `--include-from` file, one per line. Blank lines and lines
+/// starting with `#` are skipped.
+fn read_include_list(path: &Path) -> error::Result<Vec<String>> {
+    let contents = std::fs::read_to_string(path).map_err(|e| {
+        Error::io(format!(
+            "failed to read include list `{}`: {e}",
+            path.display()
+        ))
+    })?;
+    Ok(contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(ToOwned::to_owned)
+        .collect())
+}
+
+pub fn gather_paths(args: &[String]) -> error::Result<Vec<PathBuf>> {
+    if args.is_empty() {
+        return Ok(vec![PathBuf::from_str(".").unwrap()]);
+    }
+    let mut paths = Vec::default();
+    for arg in args.iter().flat_map(|arg| arg.split(';')) {
+        // remote modules would otherwise be treated as a nonexistent local path and silently ignored
+        if arg.starts_with("http://") || arg.starts_with("https://") {
+            return Err(Error::args(format!(
+                "cannot include `{arg}`: fetching modules by URL is not supported, download the module and include its local path instead"
+            )));
+        }
+        paths.push(PathBuf::from_str(arg).unwrap());
+    }
+    Ok(paths)
+}
+
+pub fn shader_type(path: &Path) -> Option<ShaderType> {
+    match path.extension() {
+        Some(v) if v.to_string_lossy().to_lowercase() == "wgsl" => Some(ShaderType::Wgsl),
+        Some(v) if v.to_string_lossy().to_lowercase() == "vert" => Some(ShaderType::GlslVertex),
+        Some(v) if v.to_string_lossy().to_lowercase() == "frag" => Some(ShaderType::GlslFragment),
+        _ => None,
+    }
+}
+
+/// Read the shader type from a `//! naga_oil: type=frag` comment at the top of `source`, for
+/// files whose extension doesn't identify their type. Only leading comment lines are considered.
+pub fn front_matter_shader_type(source: &str) -> error::Result<Option<ShaderType>> {
+    let header = source
+        .lines()
+        .map(str::trim)
+        .take_while(|line| line.is_empty() || line.starts_with("//"));
+    for line in header {
+        let Some(settings) = line
+            .strip_prefix("//!")
+            .and_then(|line| line.trim_start().strip_prefix("naga_oil:"))
+        else {
+            continue;
+        };
+        for setting in settings.split_whitespace() {
+            let Some(("type", value)) = setting.split_once('=') else {
+                continue;
+            };
+            let ty = match value.to_lowercase().as_str() {
+                "wgsl" => ShaderType::Wgsl,
+                "vert" | "vertex" => ShaderType::GlslVertex,
+                "frag" | "fragment" => ShaderType::GlslFragment,
+                _ => {
+                    return Err(Error::args(format!(
+                        "invalid shader type `{value}` in front-matter, expected `wgsl`, `vert` or `frag`"
+                    )))
+                }
+            };
+            return Ok(Some(ty));
+        }
+    }
+    Ok(None)
+}
+
+pub fn input_language(path: &Path) -> Option<ShaderLanguage> {
+    shader_type(path).map(|ty| match ty {
+        ShaderType::Wgsl => ShaderLanguage::Wgsl,
+        ShaderType::GlslVertex | ShaderType::GlslFragment => ShaderLanguage::Glsl,
+    })
+}
+
+/// The language of a scanned module, from `--module-lang` mappings or else the file extension.
+/// Files without an extension use `default`, if given.
+pub fn module_language(
+    path: &Path,
+    overrides: &[(String, ShaderLanguage)],
+    default: Option<ShaderLanguage>,
+) -> Option<ShaderLanguage> {
+    let file_name = path.file_name()?.to_string_lossy().to_lowercase();
+    overrides
+        .iter()
+        .find(|(extension, _)| file_name.ends_with(&format!(".{extension}")))
+        .map(|(_, language)| *language)
+        .or_else(|| input_language(path))
+        .or(default.filter(|_| path.extension().is_none()))
+}
+
+/// Read modules from a JSON object on stdin, mapping module names to either their source or
+/// an object `{ "source": ..., "language": "wgsl" | "glsl" }`. Sources are piped through `transform`, if given.
+pub fn read_stdin_modules(transform: Option<&str>) -> error::Result<Vec<(String, IncludedModule)>> {
+    let json: serde_json::Value = serde_json::from_reader(std::io::stdin())
+        .map_err(|e| Error::args(format!("failed to parse modules from stdin: {e}")))?;
+    let Some(object) = json.as_object() else {
+        return Err(Error::args(
+            "modules on stdin must be a JSON object mapping module names to sources",
+        ));
+    };
+
+    object
+        .iter()
+        .map(|(name, value)| {
+            let (source, language) = match value {
+                serde_json::Value::String(source) => (source.clone(), None),
+                serde_json::Value::Object(fields) => (
+                    fields
+                        .get("source")
+                        .and_then(|source| source.as_str())
+                        .ok_or_else(|| {
+                            Error::args(format!("stdin module `{name}` has no `source` string"))
+                        })?
+                        .to_owned(),
+                    fields
+                        .get("language")
+                        .and_then(|language| language.as_str()),
+                ),
+                _ => {
+                    return Err(Error::args(format!(
+                        "stdin module `{name}` must be a source string or an object"
+                    )))
+                }
+            };
+
+            let language = match language.map(str::to_lowercase).as_deref() {
+                Some("wgsl") => ShaderLanguage::Wgsl,
+                Some("glsl") => ShaderLanguage::Glsl,
+                Some(other) => {
+                    return Err(Error::args(format!(
+                        "stdin module `{name}` has unknown language `{other}`"
+                    )))
+                }
+                // only glsl sources have a `#version` directive
+                None if source.contains("#version") => ShaderLanguage::Glsl,
+                None => ShaderLanguage::Wgsl,
+            };
+
+            let path = PathBuf::from(format!("<stdin>/{name}"));
+            let source = match transform {
+                Some(command) => transform_source(command, &path, source)?,
+                None => source,
+            };
+            let (_, reqs, defines) = naga_oil::compose::get_preprocessor_data(&source);
+            let module = IncludedModule {
+                reqs: import_names(reqs),
+                path,
+                language,
+                source,
+                defines,
+                shadowed: Vec::new(),
+            };
+            Ok((name.clone(), module))
+        })
+        .collect()
+}
+
+/// The distinct imported module names, in the order they are first imported.
+pub fn import_names(imports: Vec<ImportDefinition>) -> Vec<String> {
+    let mut names: Vec<String> = Vec::default();
+    for import in imports {
+        if !names.contains(&import.import) {
+            names.push(import.import);
+        }
+    }
+    names
+}
+
+/// Lexically normalize a path, making it absolute, removing `.` components and resolving `..` components
+/// where possible, so that paths given relative to different directories can be compared.
+pub fn normalize_path(path: &Path) -> PathBuf {
+    let path = std::path::absolute(path).unwrap_or_else(|_| path.to_owned());
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => (),
+            Component::ParentDir
+                if matches!(
+                    normalized.components().next_back(),
+                    Some(Component::Normal(_))
+                ) =>
+            {
+                normalized.pop();
+            }
+            other => normalized.push(other),
+        }
+    }
+    normalized
+}
+
+/// Rewrite quoted imports which name a scanned file, rather than a module name, to use the name of
+/// the matching module. This allows `#import "util.wgsl" as u` to refer to a file which declares its
+/// own `#define_import_path`. Paths are matched as given on the command line, and also relative to
+/// `importer` if provided.
+pub fn resolve_path_imports(
+    source: &mut String,
+    reqs: &mut [String],
+    importer: Option<&Path>,
+    names_by_path: &HashMap<PathBuf, String>,
+) {
+    for req in reqs.iter_mut() {
+        if names_by_path.values().any(|name| name == req) {
+            continue;
+        }
+        let Some(path) = req.strip_prefix('"').and_then(|req| req.strip_suffix('"')) else {
+            continue;
+        };
+        let relative = importer.map(|importer| {
+            let directory = importer.parent().unwrap_or(Path::new(""));
+            normalize_path(&directory.join(path))
+        });
+        let name = std::iter::once(normalize_path(Path::new(path)))
+            .chain(relative)
+            .find_map(|path| names_by_path.get(&path));
+        if let Some(name) = name {
+            *source = rewrite_import_directives(source, req, name);
+            *req = name.clone();
+        }
+    }
+}
+
+/// Replace the quoted path `req` with the module name `name` in `#import` directives, leaving the rest
+/// of the source alone. A bare `#import "path"` becomes `#import name as "path"`, so that items the
+/// source refers to as `"path"::item` still resolve.
+fn rewrite_import_directives(source: &str, req: &str, name: &str) -> String {
+    source
+        .split_inclusive('\n')
+        .map(|line| {
+            let directive = line
+                .trim_start()
+                .strip_prefix('#')
+                .and_then(|line| line.trim_start().strip_prefix("import"))
+                .filter(|rest| rest.starts_with(char::is_whitespace));
+            match directive {
+                Some(rest) if rest.trim().trim_end_matches(';').trim_end() == req => {
+                    line.replacen(req, &format!("{name} as {req}"), 1)
+                }
+                Some(_) => line.replace(req, name),
+                None => line.to_owned(),
+            }
+        })
+        .collect()
+}
+
+/// Replace GLSL `#include "file"` directives with the contents of the scanned file they name,
+/// for `--glsl-includes`. Paths are resolved relative to `path` and then as given on the command
+/// line. Each file is inlined at most once, like an include guard, and any `#define_import_path`
+/// in an included file is dropped.
+pub fn inline_glsl_includes(
+    source: &str,
+    path: &Path,
+    sources_by_path: &HashMap<PathBuf, String>,
+) -> error::Result<String> {
+    let mut seen = HashSet::from([normalize_path(path)]);
+    inline_includes(source, path, sources_by_path, &mut seen, false)
+}
+
+fn inline_includes(
+    source: &str,
+    path: &Path,
+    sources_by_path: &HashMap<PathBuf, String>,
+    seen: &mut HashSet<PathBuf>,
+    nested: bool,
+) -> error::Result<String> {
+    let mut inlined = String::with_capacity(source.len());
+    for line in source.lines() {
+        let trimmed = line.trim();
+        if nested && trimmed.starts_with("#define_import_path") {
+            continue;
+        }
+        let Some(included) = trimmed
+            .strip_prefix("#include")
+            .map(str::trim)
+            .and_then(|rest| rest.strip_prefix('"')?.strip_suffix('"'))
+        else {
+            inlined.push_str(line);
+            inlined.push('\n');
+            continue;
+        };
+
+        let directory = path.parent().unwrap_or(Path::new(""));
+        let Some((included_path, included_source)) =
+            [directory.join(included), PathBuf::from(included)]
+                .into_iter()
+                .map(|candidate| normalize_path(&candidate))
+                .find_map(|candidate| {
+                    let source = sources_by_path.get(&candidate)?;
+                    Some((candidate, source))
+                })
+        else {
+            return Err(Error::unresolved_import(format!(
+                "`{}` includes `{included}`, which is not in the included paths",
+                path.display()
+            )));
+        };
+        if seen.insert(included_path.clone()) {
+            inlined.push_str(&inline_includes(
+                included_source,
+                &included_path,
+                sources_by_path,
+                seen,
+                true,
+            )?);
+        }
+    }
+    Ok(inlined)
+}
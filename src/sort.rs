@@ -0,0 +1,105 @@
+//! Reordering a module's global variables and functions for stable NAGA output, for `--sort-ir`.
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+use naga::{Arena, Expression, Function, Handle};
+
+use crate::visit::for_each_call;
+
+/// Reorder the global variables of `module` by name, and its functions by name as far as calls allow, as naga requires
+/// functions to come after the functions they call. Handles are updated to match, so the module is unchanged apart
+/// from the order of its arenas. Unnamed items keep their relative order after the named ones.
+///
+/// This is best effort: the other arenas (types, constants and expressions) are left in the order they were built.
+pub fn sort_module(module: &mut naga::Module) {
+    let mut globals: Vec<_> = module.global_variables.iter().collect();
+    globals.sort_by_key(|&(handle, var)| (var.name.is_none(), var.name.clone(), handle));
+    let globals: Vec<_> = globals.into_iter().map(|(handle, _)| handle).collect();
+    let globals = reorder(&mut module.global_variables, &globals);
+
+    let functions = call_order(&mut module.functions);
+    let functions = reorder(&mut module.functions, &functions);
+
+    let bodies = module
+        .functions
+        .iter_mut()
+        .map(|(_, function)| function)
+        .chain(module.entry_points.iter_mut().map(|ep| &mut ep.function));
+    for function in bodies {
+        for (_, expression) in function.expressions.iter_mut() {
+            match expression {
+                Expression::GlobalVariable(handle) => *handle = globals[handle],
+                Expression::CallResult(handle) => *handle = functions[handle],
+                _ => (),
+            }
+        }
+        for_each_call(&mut function.body, &mut |handle| {
+            *handle = functions[handle]
+        });
+    }
+}
+
+/// The handles of `functions`, with each function after those it calls and otherwise ordered by name.
+/// Functions in a cycle of calls, which naga rejects, are left at the end in their original order.
+fn call_order(functions: &mut Arena<Function>) -> Vec<Handle<Function>> {
+    let mut callees: HashMap<_, HashSet<_>> = HashMap::new();
+    let mut callers: HashMap<_, Vec<_>> = HashMap::new();
+    for (handle, function) in functions.iter_mut() {
+        let mut called = HashSet::new();
+        for_each_call(&mut function.body, &mut |callee| {
+            called.insert(*callee);
+        });
+        for &callee in called.iter() {
+            callers.entry(callee).or_default().push(handle);
+        }
+        callees.insert(handle, called);
+    }
+
+    let functions = &*functions;
+    let key = |handle: Handle<Function>| {
+        let name = functions[handle].name.clone();
+        (name.is_none(), name, handle)
+    };
+    let mut ready: BTreeSet<_> = functions
+        .iter()
+        .filter(|(handle, _)| callees[handle].is_empty())
+        .map(|(handle, _)| key(handle))
+        .collect();
+    let mut order = Vec::with_capacity(functions.len());
+    while let Some((_, _, handle)) = ready.pop_first() {
+        order.push(handle);
+        for &caller in callers.get(&handle).into_iter().flatten() {
+            let remaining = callees.get_mut(&caller).unwrap();
+            remaining.remove(&handle);
+            if remaining.is_empty() {
+                ready.insert(key(caller));
+            }
+        }
+    }
+    let placed: HashSet<_> = order.iter().copied().collect();
+    order.extend(
+        functions
+            .iter()
+            .map(|(handle, _)| handle)
+            .filter(|handle| !placed.contains(handle)),
+    );
+    order
+}
+
+/// Rebuild `arena` with its items in `order`, returning the new handle of each old one.
+fn reorder<T>(arena: &mut Arena<T>, order: &[Handle<T>]) -> HashMap<Handle<T>, Handle<T>> {
+    let spans: Vec<_> = order.iter().map(|&handle| arena.get_span(handle)).collect();
+    let mut items: Vec<_> = std::mem::take(arena)
+        .into_inner()
+        .into_iter()
+        .map(Some)
+        .collect();
+    order
+        .iter()
+        .zip(spans)
+        .map(|(&old, span)| {
+            let item = items[old.index()].take().unwrap();
+            (old, arena.append(item, span))
+        })
+        .collect()
+}
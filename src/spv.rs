@@ -0,0 +1,167 @@
+//! Post-processing of SPIR-V produced by naga's backend.
+
+use std::collections::HashMap;
+
+use naga::{back::spv::Capability, ResourceBinding};
+
+use crate::{
+    error::{self, Error},
+    visit,
+};
+
+const OP_EXTENSION: u32 = 10;
+const OP_CAPABILITY: u32 = 17;
+const HEADER_WORDS: usize = 5;
+
+/// Add `OpCapability` and `OpExtension` declarations that naga didn't emit itself.
+/// Declarations already present in the module are not duplicated.
+pub fn inject_declarations(
+    words: &mut Vec<u32>,
+    capabilities: &[Capability],
+    extensions: &[String],
+) {
+    let present = |opcode: u32, operands: &[u32], words: &[u32]| {
+        instructions(words).any(|(start, len)| {
+            words[start] & 0xffff == opcode && &words[start + 1..start + len] == operands
+        })
+    };
+
+    // OpCapability instructions come first, directly after the header
+    let mut position = end_of_leading(words, &[OP_CAPABILITY]);
+    for capability in capabilities {
+        let operands = [*capability as u32];
+        if !present(OP_CAPABILITY, &operands, words) {
+            words.splice(position..position, instruction(OP_CAPABILITY, &operands));
+            position += 2;
+        }
+    }
+
+    // followed by OpExtension instructions
+    let mut position = end_of_leading(words, &[OP_CAPABILITY, OP_EXTENSION]);
+    for extension in extensions {
+        let operands = string_words(extension);
+        if !present(OP_EXTENSION, &operands, words) {
+            let instruction = instruction(OP_EXTENSION, &operands);
+            let len = instruction.len();
+            words.splice(position..position, instruction);
+            position += len;
+        }
+    }
+}
+
+/// Move resources to the descriptor sets and bindings given by `map`, which pairs a WGSL
+/// `@group`/`@binding` with its SPIR-V set and binding. naga's SPIR-V backend writes groups
+/// as descriptor sets unchanged, so the remapping is applied to the module before writing.
+pub fn remap_bindings(
+    module: &mut naga::Module,
+    map: &[(ResourceBinding, ResourceBinding)],
+) -> error::Result<()> {
+    let mut targets = HashMap::new();
+    for (source, target) in map {
+        if let Some(previous) = targets.insert(target, source) {
+            return Err(Error::args(format!(
+                "`{}` and `{}` are both mapped to set {} binding {}",
+                binding_name(previous),
+                binding_name(source),
+                target.group,
+                target.binding
+            )));
+        }
+    }
+
+    let map: HashMap<_, _> = map.iter().cloned().collect();
+    let mut used = HashMap::new();
+    for (_, global) in module.global_variables.iter_mut() {
+        let Some(binding) = global.binding.as_mut() else {
+            continue;
+        };
+        let source = binding.clone();
+        if let Some(target) = map.get(binding) {
+            *binding = target.clone();
+        }
+        if let Some(previous) = used.insert(binding.clone(), source.clone()) {
+            return Err(Error::args(format!(
+                "`{}` and `{}` both end up at set {} binding {}",
+                binding_name(&previous),
+                binding_name(&source),
+                binding.group,
+                binding.binding
+            )));
+        }
+    }
+    Ok(())
+}
+
+fn binding_name(binding: &ResourceBinding) -> String {
+    format!("@group({}) @binding({})", binding.group, binding.binding)
+}
+
+/// Make statement spans usable as offsets into the main shader's source for SPIR-V debug info.
+/// naga_oil tags each span with the index of the module it came from. Spans from the main shader
+/// (index 0) keep their offset, which is accurate when the shader has no imports and otherwise
+/// shifted by the declarations naga_oil prepends for imported items. Spans from imported modules
+/// can't be represented, as SPIR-V debug info refers to a single source file, and are cleared.
+pub fn main_shader_spans(module: &mut naga::Module, source_len: usize) {
+    let functions = module
+        .functions
+        .iter_mut()
+        .map(|(_, function)| function)
+        .chain(
+            module
+                .entry_points
+                .iter_mut()
+                .map(|entry_point| &mut entry_point.function),
+        );
+    for function in functions {
+        visit::for_each_statement(&mut function.body, &mut |_, span| {
+            if let Some(span) = span {
+                *span = match span.to_range() {
+                    Some(range) if range.start >> error::SPAN_SHIFT == 0 => naga::Span::new(
+                        (range.start as u32).min(source_len as u32),
+                        (range.end as u32).min(source_len as u32),
+                    ),
+                    _ => naga::Span::default(),
+                };
+            }
+        });
+    }
+}
+
+/// The (start, word count) of each instruction following the header.
+fn instructions(words: &[u32]) -> impl Iterator<Item = (usize, usize)> + '_ {
+    let mut position = HEADER_WORDS;
+    std::iter::from_fn(move || {
+        let len = (*words.get(position)? >> 16) as usize;
+        if len == 0 || position + len > words.len() {
+            return None;
+        }
+        let start = position;
+        position += len;
+        Some((start, len))
+    })
+}
+
+/// The index just past the leading run of instructions with one of the given opcodes.
+fn end_of_leading(words: &[u32], opcodes: &[u32]) -> usize {
+    instructions(words)
+        .take_while(|(start, _)| opcodes.contains(&(words[*start] & 0xffff)))
+        .last()
+        .map_or(HEADER_WORDS, |(start, len)| start + len)
+}
+
+fn instruction(opcode: u32, operands: &[u32]) -> Vec<u32> {
+    let mut words = vec![((operands.len() as u32 + 1) << 16) | opcode];
+    words.extend_from_slice(operands);
+    words
+}
+
+/// A nul-terminated literal string packed into little-endian words.
+fn string_words(s: &str) -> Vec<u32> {
+    let mut bytes = s.as_bytes().to_vec();
+    bytes.push(0);
+    bytes.resize(bytes.len().div_ceil(4) * 4, 0);
+    bytes
+        .chunks(4)
+        .map(|chunk| u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]))
+        .collect()
+}
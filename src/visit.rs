@@ -0,0 +1,42 @@
+//! Walking the statements of function bodies, shared by the passes over a composed module.
+
+use naga::{Block, Function, Handle, Span, Statement};
+
+/// Call `f` with every statement in `block`, including those in nested blocks, along with its span
+/// when naga records spans. A statement is visited before the statements nested within it.
+pub fn for_each_statement(
+    block: &mut Block,
+    f: &mut impl FnMut(&mut Statement, Option<&mut Span>),
+) {
+    for (statement, span) in block.span_iter_mut() {
+        f(statement, span);
+        match statement {
+            Statement::Block(body) => for_each_statement(body, f),
+            Statement::If { accept, reject, .. } => {
+                for_each_statement(accept, f);
+                for_each_statement(reject, f);
+            }
+            Statement::Switch { cases, .. } => {
+                for case in cases.iter_mut() {
+                    for_each_statement(&mut case.body, f);
+                }
+            }
+            Statement::Loop {
+                body, continuing, ..
+            } => {
+                for_each_statement(body, f);
+                for_each_statement(continuing, f);
+            }
+            _ => (),
+        }
+    }
+}
+
+/// Call `f` with the handle of the function called by each call statement in `block`.
+pub fn for_each_call(block: &mut Block, f: &mut impl FnMut(&mut Handle<Function>)) {
+    for_each_statement(block, &mut |statement, _| {
+        if let Statement::Call { function, .. } = statement {
+            f(function);
+        }
+    });
+}